@@ -0,0 +1,118 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::SinkExt;
+use shroom_crypto::{
+    net::net_cipher::{NetCipher, CRYPT_AES},
+    RoundKey, ShroomVersion,
+};
+use shroom_net::{
+    codec::{
+        legacy::{
+            codec::{LegacyDecoder, LegacyEncoder},
+            conn::{LegacyReceiver, LegacySender},
+            framed::{LegacySink, LegacyStream},
+            LegacyCodec,
+        },
+        LocalShroomTransport,
+    },
+    ShroomStream,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const V83: ShroomVersion = ShroomVersion::new(83);
+const PACKETS: usize = 64;
+
+/// Io which discards all written data and counts the write calls
+#[derive(Clone, Default)]
+struct CountingIo(Arc<AtomicUsize>);
+
+impl AsyncRead for CountingIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for CountingIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+type Codec = LegacyCodec<CRYPT_AES, LocalShroomTransport<CountingIo>>;
+
+fn stream(io: CountingIo) -> ShroomStream<Codec> {
+    let cipher = NetCipher::<CRYPT_AES>::new(Default::default(), RoundKey::zero(), V83);
+    let (r, w) = tokio::io::split(io);
+    ShroomStream::new(
+        LegacySink::new(w, LegacySender::new(LegacyEncoder::new(cipher.clone()))),
+        LegacyStream::new(r, LegacyReceiver::new(LegacyDecoder::new(cipher))),
+    )
+}
+
+pub fn batch_benchmark(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let pkts: Vec<Bytes> = (0..PACKETS)
+        .map(|_| Bytes::from_static(&[0xFF; 64]))
+        .collect();
+
+    let io = CountingIo::default();
+    let mut sess = stream(io.clone());
+
+    // Writes per tick
+    rt.block_on(async {
+        for pkt in pkts.iter() {
+            sess.send(pkt.clone()).await.unwrap();
+        }
+        let single = io.0.swap(0, Ordering::Relaxed);
+        sess.send_batch(pkts.iter().cloned()).await.unwrap();
+        let batch = io.0.swap(0, Ordering::Relaxed);
+        println!("{PACKETS} packets: {single} writes with send, {batch} writes with send_batch");
+    });
+
+    let mut group = c.benchmark_group("ShroomStreamBatch");
+    group.throughput(Throughput::Elements(PACKETS as u64));
+    group.bench_function("send", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for pkt in pkts.iter() {
+                    sess.send(pkt.clone()).await.unwrap();
+                }
+            })
+        })
+    });
+    group.bench_function("send_batch", |b| {
+        b.iter(|| rt.block_on(sess.send_batch(pkts.iter().cloned())).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, batch_benchmark);
+criterion_main!(benches);
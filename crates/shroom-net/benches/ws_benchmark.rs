@@ -0,0 +1,105 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::{SinkExt, StreamExt};
+use shroom_crypto::{
+    net::net_cipher::{NetCipher, CRYPT_AES},
+    RoundKey, ShroomVersion,
+};
+use shroom_net::{
+    codec::{
+        legacy::{
+            codec::{LegacyDecoder, LegacyEncoder},
+            conn::{LegacyReceiver, LegacySender},
+            framed::{LegacySink, LegacyStream},
+            LegacyCodec,
+        },
+        peek::PeekTransport,
+        websocket::{WebSocketCodec, WsSink, WsStream},
+        LocalShroomTransport,
+    },
+    ShroomStream,
+};
+use shroom_pkt::Packet;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const V83: ShroomVersion = ShroomVersion::new(83);
+
+/// Io which discards all written data
+struct DiscardIo;
+
+impl AsyncRead for DiscardIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for DiscardIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+type Transport = LocalShroomTransport<DiscardIo>;
+
+fn legacy_stream() -> ShroomStream<LegacyCodec<CRYPT_AES, Transport>> {
+    let cipher = NetCipher::<CRYPT_AES>::new(Default::default(), RoundKey::zero(), V83);
+    let (r, w) = tokio::io::split(DiscardIo);
+    ShroomStream::new(
+        LegacySink::new(w, LegacySender::new(LegacyEncoder::new(cipher.clone()))),
+        LegacyStream::new(r, LegacyReceiver::new(LegacyDecoder::new(cipher))),
+    )
+}
+
+fn ws_stream() -> ShroomStream<WebSocketCodec<Transport>> {
+    let ws = tokio_websockets::ServerBuilder::new()
+        .serve(PeekTransport::new(LocalShroomTransport(DiscardIo)));
+    let (w, r) = ws.split();
+    ShroomStream::new(WsSink::new(w), WsStream::new(r))
+}
+
+pub fn sink_benchmark(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    static BYTES: &[u8; 1024 * 4] = &[0xFF; 1024 * 4];
+    let pkt = Packet::from_static(BYTES);
+
+    let mut legacy = legacy_stream();
+    let mut ws = ws_stream();
+
+    let mut group = c.benchmark_group("ShroomSink");
+    group.throughput(Throughput::Bytes(BYTES.len() as u64));
+    group.bench_function("legacy_tcp", |b| {
+        b.iter(|| rt.block_on(legacy.send(pkt.clone())).unwrap())
+    });
+    group.bench_function("ws_copy", |b| {
+        b.iter(|| rt.block_on(ws.send(pkt.clone())).unwrap())
+    });
+    group.bench_function("ws_zero_copy", |b| {
+        b.iter(|| rt.block_on(ws.send_packet(pkt.clone())).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, sink_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+//! Man-in-the-middle proxy, which logs the decrypted traffic
+//! Usage: shroom-proxy <listen_addr> <upstream_addr>
+
+use shroom_crypto::net::net_cipher::CRYPT_ALL;
+use shroom_net::{
+    codec::legacy::LegacyCodec,
+    proxy::{OpcodeHooks, ProxyDirection, ShroomProxy},
+};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(listen), Some(upstream)) = (args.next(), args.next()) else {
+        anyhow::bail!("Usage: shroom-proxy <listen_addr> <upstream_addr>");
+    };
+
+    let listener = tokio::net::TcpListener::bind(&listen).await?;
+    println!("Proxying {listen} -> {upstream}");
+
+    ShroomProxy::new(LegacyCodec::<CRYPT_ALL>::default())
+        .serve(listener, upstream, || {
+            OpcodeHooks::default()
+                .observe(|dir, pkt| {
+                    let dir = match dir {
+                        ProxyDirection::ClientToServer => "C->S",
+                        ProxyDirection::ServerToClient => "S->C",
+                    };
+                    let hex: Vec<_> = pkt.iter().map(|b| format!("{b:02X}")).collect();
+                    println!("{dir} [{}] {}", pkt.len(), hex.join(" "));
+                })
+                .observe_errors(|err| eprintln!("Session failed: {err:?}"))
+        })
+        .await?;
+    Ok(())
+}
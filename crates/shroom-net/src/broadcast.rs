@@ -0,0 +1,365 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use shroom_pkt::{pkt::EncodeMessage, Packet};
+use tokio::sync::{mpsc, Notify};
+
+use crate::{NetError, NetResult};
+
+/// Policy for a session, which doesn't drain Its outgoing queue fast enough
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowReceiverPolicy {
+    /// Drops the packet, if the queue is full
+    #[default]
+    Drop,
+    /// Disconnects the session, if the queue is full
+    /// the queue yields `NetError::QueueFull` afterwards
+    Disconnect,
+    /// Waits until the queue has space again, which
+    /// also slows down the broadcast for all other sessions
+    Backpressure,
+}
+
+/// Outcome of enqueueing a packet for a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    /// The packet was added to the queue
+    Queued,
+    /// The packet was dropped, because the queue was full
+    Dropped,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    kicked: AtomicBool,
+    kick: Notify,
+    dropped: AtomicU64,
+}
+
+/// Handle to enqueue packets for a session, the packets are
+/// encrypted by the session task when they are written
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    tx: mpsc::Sender<Packet>,
+    policy: SlowReceiverPolicy,
+    shared: Arc<Shared>,
+}
+
+impl SessionHandle {
+    /// Enqueues the packet according to the slow receiver policy,
+    /// only the `Drop` policy yields `SendOutcome::Dropped`
+    pub async fn send(&self, pkt: impl Into<Packet>) -> NetResult<SendOutcome> {
+        if self.is_closed() {
+            return Err(NetError::SessionClosed);
+        }
+
+        let pkt = pkt.into();
+        match self.policy {
+            SlowReceiverPolicy::Backpressure => self
+                .tx
+                .send(pkt)
+                .await
+                .map(|_| SendOutcome::Queued)
+                .map_err(|_| NetError::SessionClosed),
+            policy => match self.tx.try_send(pkt) {
+                Ok(()) => Ok(SendOutcome::Queued),
+                Err(mpsc::error::TrySendError::Closed(_)) => Err(NetError::SessionClosed),
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    if policy == SlowReceiverPolicy::Disconnect {
+                        self.kick();
+                        return Err(NetError::QueueFull);
+                    }
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    Ok(SendOutcome::Dropped)
+                }
+            },
+        }
+    }
+
+    /// Encodes the message and enqueues It
+    pub async fn send_msg<T: EncodeMessage>(&self, msg: T) -> NetResult<SendOutcome> {
+        self.send(msg.to_message()?).await
+    }
+
+    /// Disconnects the session, the queue yields `NetError::QueueFull`
+    pub fn kick(&self) {
+        self.shared.kicked.store(true, Ordering::Release);
+        self.shared.kick.notify_one();
+    }
+
+    /// Returns whether the session was disconnected or Its queue was dropped
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed() || self.shared.kicked.load(Ordering::Acquire)
+    }
+
+    /// Number of packets, which were dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Outgoing queue of a session, which is drained by the session task
+#[derive(Debug)]
+pub struct SessionQueue {
+    rx: mpsc::Receiver<Packet>,
+    shared: Arc<Shared>,
+}
+
+impl SessionQueue {
+    /// Receives the next packet, `None` means all handles were dropped
+    pub async fn recv(&mut self) -> NetResult<Option<Packet>> {
+        if self.shared.kicked.load(Ordering::Acquire) {
+            return Err(NetError::QueueFull);
+        }
+
+        tokio::select! {
+            biased;
+            _ = self.shared.kick.notified() => Err(NetError::QueueFull),
+            pkt = self.rx.recv() => Ok(pkt),
+        }
+    }
+
+    /// Receives up to `limit` packets into the buffer, which can be sent
+    /// with `ShroomStream::send_batch`. Returns 0 If all handles were dropped
+    pub async fn recv_batch(&mut self, buf: &mut Vec<Packet>, limit: usize) -> NetResult<usize> {
+        if self.shared.kicked.load(Ordering::Acquire) {
+            return Err(NetError::QueueFull);
+        }
+
+        tokio::select! {
+            biased;
+            _ = self.shared.kick.notified() => Err(NetError::QueueFull),
+            n = self.rx.recv_many(buf, limit) => Ok(n),
+        }
+    }
+}
+
+/// Creates the handle and queue for a session with the queue capacity
+pub fn session_queue(cap: usize, policy: SlowReceiverPolicy) -> (SessionHandle, SessionQueue) {
+    let (tx, rx) = mpsc::channel(cap);
+    let shared = Arc::<Shared>::default();
+    (
+        SessionHandle {
+            tx,
+            policy,
+            shared: shared.clone(),
+        },
+        SessionQueue { rx, shared },
+    )
+}
+
+/// Broadcasts packets to a set of sessions, the payload is encoded
+/// once and shared by all sessions
+#[derive(Debug)]
+pub struct Broadcaster<K = u64> {
+    sessions: HashMap<K, SessionHandle>,
+}
+
+impl<K> Default for Broadcaster<K> {
+    fn default() -> Self {
+        Self {
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Broadcaster<K> {
+    /// Adds the session, a previous session with the same key is replaced
+    pub fn insert(&mut self, key: K, handle: SessionHandle) -> Option<SessionHandle> {
+        self.sessions.insert(key, handle)
+    }
+
+    /// Removes the session
+    pub fn remove(&mut self, key: &K) -> Option<SessionHandle> {
+        self.sessions.remove(key)
+    }
+
+    /// Gets the session
+    pub fn get(&self, key: &K) -> Option<&SessionHandle> {
+        self.sessions.get(key)
+    }
+
+    /// Number of sessions
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Checks whether there are no sessions
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Sends the packet to all sessions except `except`, closed sessions are removed.
+    /// Returns the number of sessions, which queued the packet
+    pub async fn broadcast_except(&mut self, pkt: Packet, except: Option<&K>) -> usize {
+        let mut sent = 0;
+        let mut closed = Vec::new();
+        for (key, handle) in self.sessions.iter() {
+            if except == Some(key) {
+                continue;
+            }
+            match handle.send(pkt.clone()).await {
+                Ok(SendOutcome::Queued) => sent += 1,
+                Ok(SendOutcome::Dropped) => {}
+                Err(_) => closed.push(key.clone()),
+            }
+        }
+
+        for key in closed {
+            self.sessions.remove(&key);
+        }
+        sent
+    }
+
+    /// Sends the packet to all sessions, closed sessions are removed.
+    /// Returns the number of sessions, which queued the packet
+    pub async fn broadcast(&mut self, pkt: Packet) -> usize {
+        self.broadcast_except(pkt, None).await
+    }
+
+    /// Encodes the message once and sends It to all sessions
+    pub async fn broadcast_msg<T: EncodeMessage>(&mut self, msg: T) -> NetResult<usize> {
+        Ok(self.broadcast(msg.to_message()?.into()).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use futures::StreamExt;
+    use shroom_crypto::SharedCryptoContext;
+    use shroom_pkt::Packet;
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::{
+        codec::{
+            legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda},
+            ShroomCodec,
+        },
+        NetError,
+    };
+
+    use super::{session_queue, Broadcaster, SendOutcome, SlowReceiverPolicy};
+
+    const PORT: u16 = 1738;
+
+    fn codec() -> LegacyCodecNoShanda<turmoil::net::TcpStream> {
+        LegacyCodecNoShanda::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    #[tokio::test]
+    async fn slow_receiver() -> anyhow::Result<()> {
+        let pkt = Packet::from_static(&[1, 0]);
+
+        let (handle, mut queue) = session_queue(1, SlowReceiverPolicy::Drop);
+        assert_eq!(handle.send(pkt.clone()).await?, SendOutcome::Queued);
+        assert_eq!(handle.send(pkt.clone()).await?, SendOutcome::Dropped);
+        assert_eq!(handle.dropped(), 1);
+        assert_eq!(queue.recv().await?.as_deref(), Some(&pkt[..]));
+
+        let (handle, mut queue) = session_queue(1, SlowReceiverPolicy::Disconnect);
+        handle.send(pkt.clone()).await?;
+        assert!(matches!(
+            handle.send(pkt.clone()).await,
+            Err(NetError::QueueFull)
+        ));
+        assert!(handle.is_closed());
+        assert!(matches!(queue.recv().await, Err(NetError::QueueFull)));
+
+        let (handle, mut queue) = session_queue(1, SlowReceiverPolicy::Backpressure);
+        handle.send(pkt.clone()).await?;
+        let send = tokio::spawn({
+            let (handle, pkt) = (handle.clone(), pkt.clone());
+            async move { handle.send(pkt).await }
+        });
+        let mut buf = Vec::new();
+        assert_eq!(queue.recv_batch(&mut buf, 2).await?, 1);
+        send.await??;
+        assert_eq!(queue.recv().await?.as_deref(), Some(&pkt[..]));
+
+        drop(queue);
+        assert!(matches!(
+            handle.send(Packet::from_static(&[1, 0])).await,
+            Err(NetError::SessionClosed)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broadcast_except() -> anyhow::Result<()> {
+        let mut broadcaster = Broadcaster::default();
+        let (a, mut queue_a) = session_queue(1, SlowReceiverPolicy::Drop);
+        let (b, queue_b) = session_queue(1, SlowReceiverPolicy::Drop);
+        broadcaster.insert(0, a);
+        broadcaster.insert(1, b);
+
+        let pkt = Packet::from_static(&[1, 0]);
+        assert_eq!(broadcaster.broadcast_except(pkt.clone(), Some(&1)).await, 1);
+        // Packets dropped by a full queue are not counted
+        assert_eq!(broadcaster.broadcast_except(pkt.clone(), Some(&1)).await, 0);
+        assert!(queue_a.recv().await?.is_some());
+
+        // Closed sessions are removed
+        drop(queue_b);
+        assert_eq!(broadcaster.broadcast(pkt).await, 1);
+        assert_eq!(broadcaster.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            let mut broadcaster = Broadcaster::default();
+            for id in 0..2u64 {
+                let socket = listener.accept().await?.0;
+                let mut sess = codec().create_server(socket).await?;
+                let (handle, mut queue) = session_queue(16, SlowReceiverPolicy::Disconnect);
+                broadcaster.insert(id, handle);
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    while queue.recv_batch(&mut buf, 16).await? > 0 {
+                        sess.send_batch(buf.drain(..)).await?;
+                    }
+                    Ok::<_, NetError>(())
+                });
+            }
+
+            assert_eq!(
+                broadcaster.broadcast(Packet::from_static(&[1, 0, 1])).await,
+                2
+            );
+            assert_eq!(
+                broadcaster.broadcast(Packet::from_static(&[1, 0, 2])).await,
+                2
+            );
+            std::future::pending::<()>().await;
+            Ok(())
+        });
+
+        for client in ["client1", "client2"] {
+            sim.client(client, async move {
+                let socket = TcpStream::connect(("server", PORT)).await?;
+                let mut sess = codec().create_client(socket).await?;
+                assert_eq!(&sess.next().await.unwrap()?[..], &[1, 0, 1]);
+                assert_eq!(&sess.next().await.unwrap()?[..], &[1, 0, 2]);
+                Ok(())
+            });
+        }
+
+        sim.run().unwrap();
+        Ok(())
+    }
+}
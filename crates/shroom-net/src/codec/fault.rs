@@ -0,0 +1,359 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+use crate::NetResult;
+
+use super::ShroomTransport;
+
+/// Faults, which are injected by a `FaultTransport`, all faults are disabled by default
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    /// Seed of the RNG, the same seed injects the same faults
+    pub seed: u64,
+    /// Reads return a random number of bytes between 1 and this limit
+    pub max_read_len: Option<usize>,
+    /// Delay before each read
+    pub latency: Duration,
+    /// Random delay up to this duration, which is added to the latency
+    pub jitter: Duration,
+    /// Probability of a write being stalled and the duration of the stall
+    pub write_stall: Option<(f64, Duration)>,
+    /// Number of bytes read and written, after which the connection is reset
+    pub drop_after: Option<usize>,
+    /// Probability of a bit being flipped in a read byte
+    pub bit_flip_rate: f64,
+}
+
+impl FaultConfig {
+    /// Creates a config without faults, which uses the seed for the RNG
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            ..Self::default()
+        }
+    }
+
+    /// Splits reads at random boundaries, so that each read returns at most `max_len` bytes
+    pub fn with_max_read_len(mut self, max_len: usize) -> Self {
+        assert!(max_len > 0, "Max read length must not be zero");
+        self.max_read_len = Some(max_len);
+        self
+    }
+
+    /// Delays each read by the latency and a random jitter
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+
+    /// Stalls writes with the probability for the duration
+    pub fn with_write_stall(mut self, probability: f64, stall: Duration) -> Self {
+        self.write_stall = Some((probability, stall));
+        self
+    }
+
+    /// Resets the connection after `n` bytes were read and written
+    pub fn with_drop_after(mut self, n: usize) -> Self {
+        self.drop_after = Some(n);
+        self
+    }
+
+    /// Flips a random bit of a read byte with the probability
+    pub fn with_bit_flip_rate(mut self, rate: f64) -> Self {
+        self.bit_flip_rate = rate;
+        self
+    }
+}
+
+/// Transport, which injects faults into the inner transport to test
+/// the resilience against bad network conditions
+pub struct FaultTransport<T> {
+    inner: T,
+    cfg: FaultConfig,
+    rng: StdRng,
+    transferred: usize,
+    read_len: Option<usize>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> FaultTransport<T> {
+    pub fn new(inner: T, cfg: FaultConfig) -> Self {
+        Self {
+            inner,
+            rng: StdRng::seed_from_u64(cfg.seed),
+            cfg,
+            transferred: 0,
+            read_len: None,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Gets the inner transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Gets the number of bytes, which were read and written
+    pub fn transferred(&self) -> usize {
+        self.transferred
+    }
+
+    /// Limits the length to the bytes left before the connection is reset
+    fn limit_len(&self, len: usize) -> io::Result<usize> {
+        match self.cfg.drop_after {
+            Some(n) if self.transferred >= n => Err(io::ErrorKind::ConnectionReset.into()),
+            Some(n) => Ok(len.min(n - self.transferred)),
+            None => Ok(len),
+        }
+    }
+
+    fn read_delay(&mut self) -> Option<Duration> {
+        let jitter = if self.cfg.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.rng.gen_range(Duration::ZERO..=self.cfg.jitter)
+        };
+        Some(self.cfg.latency + jitter).filter(|d| !d.is_zero())
+    }
+
+    fn write_delay(&mut self) -> Option<Duration> {
+        let (p, stall) = self.cfg.write_stall?;
+        self.rng.gen_bool(p.clamp(0., 1.)).then_some(stall)
+    }
+
+    fn flip_bits(&mut self, data: &mut [u8]) {
+        if self.cfg.bit_flip_rate <= 0. {
+            return;
+        }
+
+        let rate = self.cfg.bit_flip_rate.min(1.);
+        for b in data.iter_mut() {
+            if self.rng.gen_bool(rate) {
+                *b ^= 1 << self.rng.gen_range(0..8);
+            }
+        }
+    }
+}
+
+/// Waits for the delay, which is created on the first poll
+fn poll_delay(
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+    create: impl FnOnce() -> Option<Duration>,
+) -> Poll<()> {
+    if delay.is_none() {
+        *delay = create().map(|d| Box::pin(tokio::time::sleep(d)));
+    }
+
+    if let Some(sleep) = delay.as_mut() {
+        ready!(sleep.as_mut().poll(cx));
+    }
+    Poll::Ready(())
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for FaultTransport<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut delay = this.read_delay.take();
+        let res = poll_delay(&mut delay, cx, || this.read_delay());
+        this.read_delay = delay;
+        ready!(res);
+
+        // Keep the length for pending reads, so the RNG is not advanced by spurious polls
+        let max_len = match this.cfg.max_read_len {
+            Some(max) => *this
+                .read_len
+                .get_or_insert_with(|| this.rng.gen_range(1..=max)),
+            None => usize::MAX,
+        };
+        let len = this.limit_len(buf.remaining().min(max_len))?;
+
+        // Read directly into the buffer, only the uninitialized part is zeroed once
+        let mut chunk = ReadBuf::new(buf.initialize_unfilled_to(len));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+        this.read_delay = None;
+        this.read_len = None;
+
+        let data = chunk.filled_mut();
+        let n = data.len();
+        this.transferred += n;
+        this.flip_bits(data);
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for FaultTransport<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut delay = this.write_delay.take();
+        let res = poll_delay(&mut delay, cx, || this.write_delay());
+        this.write_delay = delay;
+        ready!(res);
+
+        let len = this.limit_len(buf.len())?;
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.write_delay = None;
+        this.transferred += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: ShroomTransport> ShroomTransport for FaultTransport<T> {
+    type ReadHalf = tokio::io::ReadHalf<Self>;
+    type WriteHalf = tokio::io::WriteHalf<Self>;
+
+    fn peer_addr(&self) -> NetResult<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> NetResult<std::net::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        tokio::io::split(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, time::Duration};
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::{net::net_cipher::CRYPT_ALL, SharedCryptoContext};
+    use shroom_pkt::Packet;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::codec::{
+        legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodec},
+        LocalShroomTransport, ShroomCodec,
+    };
+
+    use super::{FaultConfig, FaultTransport};
+
+    type Transport = FaultTransport<LocalShroomTransport<DuplexStream>>;
+
+    fn codec() -> LegacyCodec<CRYPT_ALL, Transport> {
+        LegacyCodec::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v95(),
+        )
+    }
+
+    fn pair(cfg: FaultConfig) -> (Transport, Transport) {
+        let (client, server) = tokio::io::duplex(1024);
+        (
+            FaultTransport::new(LocalShroomTransport(client), cfg.clone()),
+            FaultTransport::new(LocalShroomTransport(server), cfg),
+        )
+    }
+
+    async fn read_chunks(cfg: FaultConfig, data: &[u8]) -> Vec<usize> {
+        let (mut client, mut server) = pair(cfg);
+        client.write_all(data).await.unwrap();
+        drop(client);
+
+        let mut chunks = Vec::new();
+        let mut buf = [0; 64];
+        loop {
+            match server.read(&mut buf).await.unwrap() {
+                0 => break chunks,
+                n => chunks.push(n),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn split_reads() {
+        let cfg = FaultConfig::new(7).with_max_read_len(5);
+        let chunks = read_chunks(cfg.clone(), &[0; 100]).await;
+        assert_eq!(chunks.iter().sum::<usize>(), 100);
+        assert!(chunks.iter().all(|&n| (1..=5).contains(&n)));
+        // Same seed results in the same boundaries
+        assert_eq!(chunks, read_chunks(cfg, &[0; 100]).await);
+    }
+
+    #[tokio::test]
+    async fn drop_after() {
+        let (mut client, mut server) = pair(FaultConfig::new(1).with_drop_after(10));
+        client.write_all(&[1; 8]).await.unwrap();
+        let err = client.write_all(&[2; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(client.transferred(), 10);
+
+        let mut buf = [0; 10];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 1, 1, 1, 1, 1, 1, 1, 2, 2]);
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn bit_flips() {
+        let (mut client, mut server) = pair(FaultConfig::new(3).with_bit_flip_rate(1.));
+        client.write_all(&[0; 32]).await.unwrap();
+        let mut buf = [0; 32];
+        server.read_exact(&mut buf).await.unwrap();
+        assert!(buf.iter().all(|b| b.count_ones() == 1));
+    }
+
+    #[tokio::test]
+    async fn legacy_echo() -> anyhow::Result<()> {
+        // Tiny reads hit the partial header and partial body paths of the decoder
+        for seed in 0..4 {
+            let cfg = FaultConfig::new(seed)
+                .with_max_read_len(3)
+                .with_latency(Duration::ZERO, Duration::from_micros(200))
+                .with_write_stall(0.1, Duration::from_millis(1));
+            let (client, server) = pair(cfg);
+
+            let server = tokio::spawn(async move {
+                let mut sess = codec().create_server(server).await?;
+                while let Some(pkt) = sess.next().await {
+                    sess.send(pkt?).await?;
+                }
+                anyhow::Ok(())
+            });
+
+            let mut sess = codec().create_client(client).await?;
+            for data in [&[1, 0][..], &[2, 0, 1, 2, 3], &[0xAB; 128]] {
+                sess.send(Packet::from_static(data)).await?;
+                assert_eq!(&sess.next().await.unwrap()?[..], data);
+            }
+
+            drop(sess);
+            server.await??;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use shroom_crypto::SharedCryptoContext;
+use shroom_pkt::{pkt::EncodeMessage, Packet};
+
+use crate::NetResult;
+
+use super::{
+    conn::{client_codec, LegacyReceiver, LegacySender},
+    handshake::{Handshake, HandshakeFormat},
+    handshake_policy::HandshakePolicy,
+};
+
+const READ_CHUNK_LEN: usize = 4096;
+
+/// Blocking client for the legacy protocol over a `std::net::TcpStream`,
+/// which can be used without a tokio runtime
+pub struct BlockingShroomClient<const C: u8> {
+    stream: TcpStream,
+    handshake: Handshake,
+    tx: LegacySender<C>,
+    rx: LegacyReceiver<C>,
+}
+
+impl<const C: u8> BlockingShroomClient<C> {
+    /// Connects to the server and reads the handshake with the default format
+    pub fn connect(addr: impl ToSocketAddrs, crypto_ctx: SharedCryptoContext) -> NetResult<Self> {
+        Self::from_stream(TcpStream::connect(addr)?, crypto_ctx)
+    }
+
+    /// Connects to the server within the timeout, which is also used as read and write timeout
+    pub fn connect_timeout(
+        addr: &SocketAddr,
+        timeout: Duration,
+        crypto_ctx: SharedCryptoContext,
+    ) -> NetResult<Self> {
+        let stream = TcpStream::connect_timeout(addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Self::from_stream(stream, crypto_ctx)
+    }
+
+    /// Creates a client from a connected stream and reads the handshake with the default format
+    pub fn from_stream(mut stream: TcpStream, crypto_ctx: SharedCryptoContext) -> NetResult<Self> {
+        let hshake = Handshake::read_handshake(&mut stream)?;
+        Ok(Self::new(stream, crypto_ctx, hshake))
+    }
+
+    /// Creates a client from a connected stream, the handshake is read with the format
+    /// and validated with the policy
+    pub fn from_stream_with_format(
+        mut stream: TcpStream,
+        crypto_ctx: SharedCryptoContext,
+        handshake_fmt: &impl HandshakeFormat,
+        handshake_policy: &HandshakePolicy,
+    ) -> NetResult<Self> {
+        let hshake = handshake_fmt.read_handshake(&mut stream)?;
+        handshake_policy.validate(&hshake)?;
+        Ok(Self::new(stream, crypto_ctx, hshake))
+    }
+
+    fn new(stream: TcpStream, crypto_ctx: SharedCryptoContext, handshake: Handshake) -> Self {
+        let (enc, dec) = client_codec(&crypto_ctx, &handshake);
+        Self {
+            stream,
+            handshake,
+            tx: LegacySender::new(enc),
+            rx: LegacyReceiver::new(dec),
+        }
+    }
+
+    /// Gets the handshake, which was received from the server
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// Gets the underlying stream
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Gets the address of the server
+    pub fn peer_addr(&self) -> NetResult<SocketAddr> {
+        Ok(self.stream.peer_addr()?)
+    }
+
+    /// Gets the local address
+    pub fn local_addr(&self) -> NetResult<SocketAddr> {
+        Ok(self.stream.local_addr()?)
+    }
+
+    /// Sets the read timeout, If It expires `recv` returns an IO error
+    /// and can be called again
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> NetResult<()> {
+        Ok(self.stream.set_read_timeout(timeout)?)
+    }
+
+    /// Sets the write timeout, If It expires the client must not be used anymore,
+    /// because the packet might be sent partially
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> NetResult<()> {
+        Ok(self.stream.set_write_timeout(timeout)?)
+    }
+
+    fn flush_tx(&mut self) -> NetResult<()> {
+        let out = self.tx.take_output();
+        Ok(self.stream.write_all(&out)?)
+    }
+
+    /// Encrypts and sends the packet
+    pub fn send(&mut self, data: &[u8]) -> NetResult<()> {
+        self.tx.send(data)?;
+        self.flush_tx()
+    }
+
+    /// Encodes, encrypts and sends the message
+    pub fn send_msg<T: EncodeMessage>(&mut self, msg: T) -> NetResult<()> {
+        self.tx.send_msg(msg)?;
+        self.flush_tx()
+    }
+
+    /// Receives the next packet, blocks until It is received or the read timeout expires
+    pub fn recv(&mut self) -> NetResult<Packet> {
+        loop {
+            if let Some(pkt) = self.rx.poll_packet()? {
+                return Ok(pkt);
+            }
+
+            let mut buf = [0; READ_CHUNK_LEN];
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            self.rx.receive(&buf[..n]);
+        }
+    }
+
+    /// Shuts down both directions of the connection
+    pub fn shutdown(&self) -> NetResult<()> {
+        Ok(self.stream.shutdown(Shutdown::Both)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, time::Duration};
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::{net::net_cipher::CRYPT_ALL, SharedCryptoContext};
+    use tokio::net::TcpListener;
+
+    use crate::{
+        codec::legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodec},
+        NetError,
+    };
+
+    use super::BlockingShroomClient;
+
+    #[tokio::test]
+    async fn blocking_echo() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let codec = LegacyCodec::<CRYPT_ALL>::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v95(),
+            );
+            let mut sess = codec.accept(listener.accept().await?.0).await?;
+            while let Some(Ok(pkt)) = sess.next().await {
+                // Packets with only an opcode are not answered to test the read timeout
+                if pkt.len() > 2 {
+                    sess.send(pkt).await?;
+                }
+            }
+            anyhow::Ok(())
+        });
+
+        tokio::task::spawn_blocking(move || {
+            let mut client =
+                BlockingShroomClient::<CRYPT_ALL>::connect(addr, SharedCryptoContext::default())?;
+            assert_eq!(client.peer_addr()?, addr);
+            for data in [&[1, 0, 1][..], &[0xFF; 8192], &[2, 0, 3]] {
+                client.send(data)?;
+                assert_eq!(&client.recv()?[..], data);
+            }
+
+            client.set_read_timeout(Some(Duration::from_millis(50)))?;
+            client.send(&[1, 0])?;
+            let err = client.recv().unwrap_err();
+            assert!(matches!(
+                err,
+                NetError::IO(ref err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+            ));
+
+            // The client is still usable after the timeout
+            client.send(&[3, 0, 4])?;
+            assert_eq!(&client.recv()?[..], &[3, 0, 4]);
+            client.shutdown()?;
+            anyhow::Ok(())
+        })
+        .await??;
+
+        server.await??;
+        Ok(())
+    }
+}
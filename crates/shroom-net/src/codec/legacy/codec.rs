@@ -2,7 +2,7 @@ use std::ops::Deref;
 
 use bytes::{BufMut, BytesMut};
 use shroom_crypto::{net::net_cipher::NetCipher, PacketHeader, PACKET_HEADER_LEN};
-use shroom_pkt::Packet;
+use shroom_pkt::{pkt::EncodeMessage, Packet};
 use tokio_util::codec::{Decoder, Encoder};
 
 use crate::{NetError, NetResult};
@@ -128,22 +128,47 @@ impl<'a, const C: u8> Encoder<&'a [u8]> for LegacyEncoder<C> {
     }
 }
 
-/* 
+impl<const C: u8> LegacyEncoder<C> {
+    /// Encodes the message directly into the write buffer
+    pub fn encode_msg<T: EncodeMessage>(&mut self, msg: T, dst: &mut BytesMut) -> NetResult<()> {
+        // Reserve the header, encode the message behind It and encrypt It in place
+        let start = dst.len();
+        dst.put_bytes(0, PACKET_HEADER_LEN);
+        let res = msg
+            .encode_message(&mut *dst)
+            .map_err(NetError::from)
+            .and_then(|_| check_packet_len(dst.len() - start - PACKET_HEADER_LEN));
+        let cnt = match res {
+            Ok(cnt) => cnt,
+            Err(err) => {
+                dst.truncate(start);
+                return Err(err);
+            }
+        };
+
+        let (hdr, data) = dst[start..].split_at_mut(PACKET_HEADER_LEN);
+        hdr.copy_from_slice(&self.0.encode_header(cnt as u16));
+        self.0.encrypt(data);
+        Ok(())
+    }
+}
+
+/*
 impl<'a, const S: bool> futures::Sink<&'a [u8]> for LegacyEncoder<S> {
     type Error = NetError;
-    
+
     fn poll_ready(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
         <Encoder<&'a [u8]>>::poll_re
     }
-    
+
     fn start_send(self: std::pin::Pin<&mut Self>, item: &'a [u8]) -> Result<(), Self::Error> {
         todo!()
     }
-    
+
     fn poll_flush(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
         todo!()
     }
-    
+
     fn poll_close(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
         todo!()
     }
@@ -172,4 +197,49 @@ mod tests {
         unsafe { copy_crypt(&mut buf, &[0; 0], plus_one) };
         assert_eq!(buf[..], [1, 1, 1]);
     }
+
+    #[test]
+    fn encode_msg() {
+        use shroom_crypto::{net::net_cipher::CRYPT_ALL, RoundKey, SharedCryptoContext};
+        use shroom_pkt::{pkt::EncodeMessage, EncodePacket, HasOpCode, PacketResult, PacketWriter};
+
+        struct Msg(u32);
+
+        impl EncodePacket for Msg {
+            const SIZE_HINT: shroom_pkt::SizeHint = u32::SIZE_HINT;
+
+            fn encode_len(&self) -> usize {
+                self.0.encode_len()
+            }
+
+            fn encode<B: BufMut>(&self, pw: &mut PacketWriter<B>) -> PacketResult<()> {
+                self.0.encode(pw)
+            }
+        }
+
+        impl HasOpCode for Msg {
+            type OpCode = u16;
+
+            const OPCODE: u16 = 0x10;
+        }
+
+        let cipher = || {
+            NetCipher::<CRYPT_ALL>::new(
+                SharedCryptoContext::default(),
+                RoundKey::new([1, 2, 3, 4]),
+                95.into(),
+            )
+        };
+
+        // Encoding the message must match encoding Its bytes
+        let mut a = BytesMut::new();
+        LegacyEncoder(cipher())
+            .encode_msg(Msg(0xAABBCCDD), &mut a)
+            .unwrap();
+        let mut b = BytesMut::new();
+        let msg = Msg(0xAABBCCDD).to_message().unwrap();
+        LegacyEncoder(cipher()).encode(&msg[..], &mut b).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.len(), PACKET_HEADER_LEN + 6);
+    }
 }
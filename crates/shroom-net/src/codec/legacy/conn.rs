@@ -0,0 +1,331 @@
+use bytes::{Buf, Bytes, BytesMut};
+use shroom_crypto::{net::net_cipher::NetCipher, SharedCryptoContext};
+use shroom_pkt::{pkt::EncodeMessage, Packet, PacketReader};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{NetError, NetResult};
+
+use super::{
+    codec::{LegacyDecoder, LegacyEncoder},
+    handshake::{DefaultHandshakeFormat, Handshake, HandshakeFormat},
+};
+
+/// Creates the encoder and decoder for the client from the handshake,
+/// the handshake is recorded in the current session span
+pub fn client_codec<const C: u8>(
+    crypto_ctx: &SharedCryptoContext,
+    hshake: &Handshake,
+) -> (LegacyEncoder<C>, LegacyDecoder<C>) {
+    #[cfg(feature = "tracing")]
+    crate::trace::record_handshake(hshake);
+    let v = hshake.version;
+    (
+        LegacyEncoder::new(NetCipher::new(crypto_ctx.clone(), hshake.iv_enc, v)),
+        LegacyDecoder::new(NetCipher::new(
+            crypto_ctx.clone(),
+            hshake.iv_dec,
+            v.invert(),
+        )),
+    )
+}
+
+/// Creates the encoder and decoder for the server from the handshake,
+/// the handshake is recorded in the current session span
+pub fn server_codec<const C: u8>(
+    crypto_ctx: &SharedCryptoContext,
+    hshake: &Handshake,
+) -> (LegacyEncoder<C>, LegacyDecoder<C>) {
+    #[cfg(feature = "tracing")]
+    crate::trace::record_handshake(hshake);
+    let v = hshake.version;
+    (
+        LegacyEncoder::new(NetCipher::new(
+            crypto_ctx.clone(),
+            hshake.iv_dec,
+            v.invert(),
+        )),
+        LegacyDecoder::new(NetCipher::new(crypto_ctx.clone(), hshake.iv_enc, v)),
+    )
+}
+
+/// Event emitted by a `LegacyConnection`
+#[derive(Debug)]
+pub enum LegacyEvent {
+    /// Handshake was received from the server, only emitted on the client
+    Handshake(Handshake),
+    /// Decrypted packet
+    Packet(Packet),
+}
+
+/// Sending half of an established connection, which encrypts
+/// queued packets into the output buffer
+pub struct LegacySender<const C: u8> {
+    enc: LegacyEncoder<C>,
+    tx: BytesMut,
+}
+
+impl<const C: u8> LegacySender<C> {
+    pub fn new(enc: LegacyEncoder<C>) -> Self {
+        Self {
+            enc,
+            tx: BytesMut::new(),
+        }
+    }
+
+    /// Encrypts the packet into the output buffer
+    pub fn send(&mut self, data: &[u8]) -> NetResult<()> {
+        self.enc.encode(data, &mut self.tx)
+    }
+
+    /// Encodes and encrypts the message into the output buffer
+    pub fn send_msg<T: EncodeMessage>(&mut self, msg: T) -> NetResult<()> {
+        self.enc.encode_msg(msg, &mut self.tx)
+    }
+
+    /// Gets the bytes, which have to be transmitted
+    pub fn output(&self) -> &[u8] {
+        &self.tx
+    }
+
+    /// Marks the first `n` bytes of the output as transmitted
+    pub fn consume_output(&mut self, n: usize) {
+        self.tx.advance(n);
+    }
+
+    /// Takes all bytes, which have to be transmitted
+    pub fn take_output(&mut self) -> Bytes {
+        self.tx.split().freeze()
+    }
+}
+
+/// Receiving half of an established connection, which decrypts
+/// the received bytes into packets
+pub struct LegacyReceiver<const C: u8> {
+    dec: LegacyDecoder<C>,
+    rx: BytesMut,
+}
+
+impl<const C: u8> LegacyReceiver<C> {
+    pub fn new(dec: LegacyDecoder<C>) -> Self {
+        Self {
+            dec,
+            rx: BytesMut::new(),
+        }
+    }
+
+    /// Passes received bytes to the receiver
+    pub fn receive(&mut self, data: &[u8]) {
+        self.rx.extend_from_slice(data);
+    }
+
+    /// Gets the buffer of the received bytes, which allows
+    /// readers to read into It without copying
+    pub fn receive_buf(&mut self) -> &mut BytesMut {
+        &mut self.rx
+    }
+
+    /// Number of received bytes, which were not decoded yet
+    pub fn pending(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// Decodes the next packet from the received bytes,
+    /// returns `None` If more bytes are required
+    pub fn poll_packet(&mut self) -> NetResult<Option<Packet>> {
+        self.dec.decode(&mut self.rx)
+    }
+}
+
+/// Sans-IO state machine of a legacy connection. Received bytes are passed in with
+/// `receive` and turned into events by `poll_event`, queued packets are encrypted
+/// into the output buffer, which the caller has to transmit.
+/// Once established It can be split into a `LegacySender` and `LegacyReceiver`
+pub struct LegacyConnection<const C: u8, F = DefaultHandshakeFormat> {
+    crypto_ctx: SharedCryptoContext,
+    handshake_fmt: F,
+    handshake: Option<Handshake>,
+    /// Bytes received before the handshake
+    rx: BytesMut,
+    halves: Option<(LegacySender<C>, LegacyReceiver<C>)>,
+}
+
+impl<const C: u8, F: HandshakeFormat> LegacyConnection<C, F> {
+    /// Creates the client side, which waits for the handshake of the server
+    pub fn client(crypto_ctx: SharedCryptoContext, handshake_fmt: F) -> Self {
+        Self {
+            crypto_ctx,
+            handshake_fmt,
+            handshake: None,
+            rx: BytesMut::new(),
+            halves: None,
+        }
+    }
+
+    /// Creates the server side, the handshake is queued as the first output
+    pub fn server(
+        crypto_ctx: SharedCryptoContext,
+        handshake_fmt: F,
+        hshake: Handshake,
+    ) -> NetResult<Self> {
+        let buf = handshake_fmt.to_buf(&hshake)?;
+        let (enc, dec) = server_codec(&crypto_ctx, &hshake);
+        let mut tx = LegacySender::new(enc);
+        tx.tx.extend_from_slice(&buf);
+        Ok(Self {
+            crypto_ctx,
+            handshake_fmt,
+            handshake: Some(hshake),
+            rx: BytesMut::new(),
+            halves: Some((tx, LegacyReceiver::new(dec))),
+        })
+    }
+
+    /// Gets the handshake, once It was sent or received
+    pub fn handshake(&self) -> Option<&Handshake> {
+        self.handshake.as_ref()
+    }
+
+    /// Checks whether the handshake was completed
+    pub fn is_established(&self) -> bool {
+        self.halves.is_some()
+    }
+
+    /// Passes received bytes to the connection
+    pub fn receive(&mut self, data: &[u8]) {
+        match self.halves.as_mut() {
+            Some((_, rx)) => rx.receive(data),
+            None => self.rx.extend_from_slice(data),
+        }
+    }
+
+    /// Decodes the next event from the received bytes,
+    /// returns `None` If more bytes are required
+    pub fn poll_event(&mut self) -> NetResult<Option<LegacyEvent>> {
+        let Some((_, rx)) = self.halves.as_mut() else {
+            return self.poll_handshake();
+        };
+
+        Ok(rx.poll_packet()?.map(LegacyEvent::Packet))
+    }
+
+    fn poll_handshake(&mut self) -> NetResult<Option<LegacyEvent>> {
+        let Some(ln) = self.rx.get(..2) else {
+            return Ok(None);
+        };
+        let ln = self.handshake_fmt.decode_handshake_len([ln[0], ln[1]])?;
+        let Some(data) = self.rx.get(2..2 + ln) else {
+            return Ok(None);
+        };
+
+        let hshake = self
+            .handshake_fmt
+            .decode_handshake(&mut PacketReader::new(data))?;
+        self.rx.advance(2 + ln);
+
+        // Bytes received after the handshake belong to the first packets
+        let (enc, dec) = client_codec(&self.crypto_ctx, &hshake);
+        let mut rx = LegacyReceiver::new(dec);
+        rx.rx = std::mem::take(&mut self.rx);
+        self.halves = Some((LegacySender::new(enc), rx));
+        self.handshake = Some(hshake.clone());
+        Ok(Some(LegacyEvent::Handshake(hshake)))
+    }
+
+    fn sender(&mut self) -> NetResult<&mut LegacySender<C>> {
+        self.halves
+            .as_mut()
+            .map(|(tx, _)| tx)
+            .ok_or(NetError::HandshakePending)
+    }
+
+    /// Encrypts the packet into the output buffer
+    pub fn send(&mut self, data: &[u8]) -> NetResult<()> {
+        self.sender()?.send(data)
+    }
+
+    /// Encodes and encrypts the message into the output buffer
+    pub fn send_msg<T: EncodeMessage>(&mut self, msg: T) -> NetResult<()> {
+        self.sender()?.send_msg(msg)
+    }
+
+    /// Gets the bytes, which have to be transmitted
+    pub fn output(&self) -> &[u8] {
+        self.halves.as_ref().map_or(&[], |(tx, _)| tx.output())
+    }
+
+    /// Marks the first `n` bytes of the output as transmitted
+    pub fn consume_output(&mut self, n: usize) {
+        if let Some((tx, _)) = self.halves.as_mut() {
+            tx.consume_output(n);
+        }
+    }
+
+    /// Takes all bytes, which have to be transmitted
+    pub fn take_output(&mut self) -> Bytes {
+        self.halves
+            .as_mut()
+            .map(|(tx, _)| tx.take_output())
+            .unwrap_or_default()
+    }
+
+    /// Splits the established connection into Its sending and receiving half
+    pub fn split(self) -> NetResult<(LegacySender<C>, LegacyReceiver<C>)> {
+        self.halves.ok_or(NetError::HandshakePending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shroom_crypto::{net::net_cipher::CRYPT_ALL, SharedCryptoContext};
+
+    use crate::{
+        codec::legacy::{
+            handshake::DefaultHandshakeFormat,
+            handshake_gen::{BasicHandshakeGenerator, HandshakeGenerator},
+        },
+        NetError,
+    };
+
+    use super::{LegacyConnection, LegacyEvent};
+
+    type Conn = LegacyConnection<CRYPT_ALL>;
+
+    #[test]
+    fn conn() -> anyhow::Result<()> {
+        let ctx = SharedCryptoContext::default();
+        let hshake = BasicHandshakeGenerator::v95().generate_handshake();
+        let mut server = Conn::server(ctx.clone(), DefaultHandshakeFormat, hshake.clone())?;
+        let mut client = Conn::client(ctx, DefaultHandshakeFormat);
+        assert!(matches!(
+            client.send(&[1, 0]),
+            Err(NetError::HandshakePending)
+        ));
+
+        server.send(&[1, 0, 1])?;
+        server.send(&[2, 0])?;
+
+        // Feed the output byte by byte to check partial reads
+        let out = server.take_output();
+        let mut events = Vec::new();
+        for b in out.iter() {
+            client.receive(&[*b]);
+            while let Some(ev) = client.poll_event()? {
+                events.push(ev);
+            }
+        }
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], LegacyEvent::Handshake(h) if *h == hshake));
+        assert!(matches!(&events[1], LegacyEvent::Packet(p) if p[..] == [1, 0, 1]));
+        assert!(matches!(&events[2], LegacyEvent::Packet(p) if p[..] == [2, 0]));
+        assert_eq!(client.handshake(), Some(&hshake));
+
+        client.send(&[3, 0, 3])?;
+        let out = client.output().to_vec();
+        client.consume_output(out.len());
+        assert!(client.output().is_empty());
+        server.receive(&out);
+        assert!(matches!(server.poll_event()?, Some(LegacyEvent::Packet(p)) if p[..] == [3, 0, 3]));
+        assert!(server.poll_event()?.is_none());
+        Ok(())
+    }
+}
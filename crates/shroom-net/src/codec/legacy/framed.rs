@@ -0,0 +1,138 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures::{Sink, Stream};
+use shroom_pkt::{pkt::EncodeMessage, Packet};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::poll_read_buf;
+
+use crate::{codec::ShroomSink, NetError, NetResult};
+
+use super::conn::{LegacyReceiver, LegacySender};
+
+/// Size of the read buffer and the output size, at which `poll_ready` flushes first
+const BUF_LEN: usize = 8 * 1024;
+
+/// Sink, which queues the packets on a `LegacySender` and writes Its output
+pub struct LegacySink<W, const C: u8> {
+    w: W,
+    tx: LegacySender<C>,
+}
+
+impl<W, const C: u8> LegacySink<W, C> {
+    pub fn new(w: W, tx: LegacySender<C>) -> Self {
+        Self { w, tx }
+    }
+
+    /// Gets the sender
+    pub fn sender(&self) -> &LegacySender<C> {
+        &self.tx
+    }
+}
+
+impl<W: AsyncWrite + Unpin, const C: u8> LegacySink<W, C> {
+    /// Writes the whole output of the sender
+    fn poll_write_output(&mut self, cx: &mut Context<'_>) -> Poll<NetResult<()>> {
+        while !self.tx.output().is_empty() {
+            let n = ready!(Pin::new(&mut self.w).poll_write(cx, self.tx.output()))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero).into()));
+            }
+            self.tx.consume_output(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a, W: AsyncWrite + Unpin, const C: u8> Sink<&'a [u8]> for LegacySink<W, C> {
+    type Error = NetError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.tx.output().len() >= BUF_LEN {
+            ready!(this.poll_write_output(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: &'a [u8]) -> Result<(), Self::Error> {
+        self.get_mut().tx.send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_output(cx))?;
+        Poll::Ready(Ok(ready!(Pin::new(&mut this.w).poll_flush(cx))?))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_output(cx))?;
+        Poll::Ready(Ok(ready!(Pin::new(&mut this.w).poll_shutdown(cx))?))
+    }
+}
+
+impl<W: AsyncWrite + Unpin, const C: u8> ShroomSink for LegacySink<W, C> {
+    fn start_send_msg<T: EncodeMessage>(self: Pin<&mut Self>, msg: T) -> NetResult<()> {
+        self.get_mut().tx.send_msg(msg)
+    }
+}
+
+/// Stream, which reads into a `LegacyReceiver` and yields the decoded packets.
+/// The stream ends after the first error
+pub struct LegacyStream<R, const C: u8> {
+    r: R,
+    rx: LegacyReceiver<C>,
+    done: bool,
+}
+
+impl<R, const C: u8> LegacyStream<R, C> {
+    pub fn new(r: R, mut rx: LegacyReceiver<C>) -> Self {
+        rx.receive_buf().reserve(BUF_LEN);
+        Self { r, rx, done: false }
+    }
+
+    /// Gets the receiver
+    pub fn receiver(&self) -> &LegacyReceiver<C> {
+        &self.rx
+    }
+}
+
+impl<R: AsyncRead + Unpin, const C: u8> Stream for LegacyStream<R, C> {
+    type Item = NetResult<Packet>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        while !this.done {
+            let res = match this.rx.poll_packet() {
+                Ok(Some(pkt)) => return Poll::Ready(Some(Ok(pkt))),
+                Ok(None) => {
+                    let buf = this.rx.receive_buf();
+                    buf.reserve(1);
+                    ready!(poll_read_buf(Pin::new(&mut this.r), cx, buf)).map_err(NetError::from)
+                }
+                Err(err) => Err(err),
+            };
+
+            match res {
+                Ok(0) => {
+                    this.done = true;
+                    // The peer closed the connection in the middle of a packet
+                    if this.rx.pending() > 0 {
+                        let err = io::Error::other("bytes remaining on stream");
+                        return Poll::Ready(Some(Err(err.into())));
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+        Poll::Ready(None)
+    }
+}
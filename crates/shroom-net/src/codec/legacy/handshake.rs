@@ -1,15 +1,21 @@
-use std::{io::Read, iter};
+use std::{future::Future, io::Read};
 
 use arrayvec::{ArrayString, ArrayVec};
+use bytes::BufMut;
 use shroom_crypto::{RoundKey, ShroomVersion, ROUND_KEY_LEN};
 use shroom_pkt::{packet_wrap, DecodePacket, EncodePacket, PacketReader, PacketWriter};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::{NetError, NetResult};
 
-use super::{LocaleCode, MAX_HANDSHAKE_LEN};
+use super::{LocaleCode, MAX_EXT_HANDSHAKE_LEN, MAX_HANDSHAKE_LEN};
 
-const HS_BUF_LEN: usize = MAX_HANDSHAKE_LEN + 2;
+/// Max length of the sub version
+pub const MAX_SUB_VERSION_LEN: usize = 16;
+/// Max number of trailing bytes
+pub const MAX_HANDSHAKE_EXTRA_LEN: usize = 32;
+
+const HS_BUF_LEN: usize = MAX_EXT_HANDSHAKE_LEN + 2;
 
 /// Handshake buffer
 pub type HandshakeBuf = ArrayVec<u8, HS_BUF_LEN>;
@@ -19,21 +25,56 @@ pub type HandshakeBuf = ArrayVec<u8, HS_BUF_LEN>;
 pub struct Handshake {
     /// Version
     pub version: ShroomVersion,
-    // Subversion up to a length of 2
-    pub sub_version: ArrayString<2>,
+    // Subversion or patch string
+    pub sub_version: ArrayString<MAX_SUB_VERSION_LEN>,
     /// Encrypt IV
     pub iv_enc: RoundKey,
     /// Decrypt IV
     pub iv_dec: RoundKey,
     /// Locale
     pub locale: LocaleCode,
+    /// Trailing bytes after the locale, only supported by extended formats
+    pub extra: ArrayVec<u8, MAX_HANDSHAKE_EXTRA_LEN>,
 }
 
 impl Handshake {
+    /// Read a handshake from the underlying reader async
+    pub async fn read_handshake_async<R: AsyncRead + Unpin + Send>(r: R) -> NetResult<Self> {
+        DefaultHandshakeFormat.read_handshake_async(r).await
+    }
+
+    /// Read a shandshake from the underlying reader
+    pub fn read_handshake<R: Read>(r: R) -> NetResult<Self> {
+        DefaultHandshakeFormat.read_handshake(r)
+    }
+
+    /// Encode the handshake onto the buffer with the default format,
+    /// fails if the handshake uses fields the default format can't encode
+    pub fn to_buf(&self) -> NetResult<HandshakeBuf> {
+        DefaultHandshakeFormat.to_buf(self)
+    }
+}
+
+/// Format of the handshake, which is sent with a 2 byte length prefix
+pub trait HandshakeFormat {
+    /// Max length of the handshake without the length prefix,
+    /// must not exceed `MAX_EXT_HANDSHAKE_LEN`
+    fn max_len(&self) -> usize;
+
+    /// Encodes the handshake without the length prefix
+    fn encode_handshake<B: BufMut>(
+        &self,
+        hshake: &Handshake,
+        pw: &mut PacketWriter<B>,
+    ) -> NetResult<()>;
+
+    /// Decodes the handshake without the length prefix
+    fn decode_handshake(&self, pr: &mut PacketReader<'_>) -> NetResult<Handshake>;
+
     /// Decode the handshake length
-    fn decode_handshake_len(data: [u8; 2]) -> NetResult<usize> {
+    fn decode_handshake_len(&self, data: [u8; 2]) -> NetResult<usize> {
         let ln = u16::from_le_bytes(data) as usize;
-        if ln <= MAX_HANDSHAKE_LEN {
+        if ln <= self.max_len().min(MAX_EXT_HANDSHAKE_LEN) {
             Ok(ln)
         } else {
             Err(NetError::HandshakeSize(ln))
@@ -41,46 +82,147 @@ impl Handshake {
     }
 
     /// Read a handshake from the underlying reader async
-    pub async fn read_handshake_async<R: AsyncRead + Unpin>(mut r: R) -> NetResult<Self> {
-        let mut ln_data = [0u8; 2];
-        r.read_exact(&mut ln_data).await?;
-        let ln = Self::decode_handshake_len(ln_data)?;
+    fn read_handshake_async<R: AsyncRead + Unpin + Send>(
+        &self,
+        mut r: R,
+    ) -> impl Future<Output = NetResult<Handshake>> + Send
+    where
+        Self: Sync,
+    {
+        async move {
+            let mut ln_data = [0u8; 2];
+            r.read_exact(&mut ln_data).await?;
+            let ln = self.decode_handshake_len(ln_data)?;
 
-        let mut handshake_data = [0u8; MAX_HANDSHAKE_LEN];
-        r.read_exact(&mut handshake_data[..ln]).await?;
-        Self::decode_complete(&mut PacketReader::new(&handshake_data[..ln]))
-            .map_err(|_| NetError::InvalidHandshake)
+            let mut handshake_data = [0u8; MAX_EXT_HANDSHAKE_LEN];
+            r.read_exact(&mut handshake_data[..ln]).await?;
+            self.decode_handshake(&mut PacketReader::new(&handshake_data[..ln]))
+        }
     }
 
-    /// Read a shandshake from the underlying reader
-    pub fn read_handshake<R: Read>(mut r: R) -> NetResult<Self> {
+    /// Read a handshake from the underlying reader
+    fn read_handshake<R: Read>(&self, mut r: R) -> NetResult<Handshake> {
         let mut ln_data = [0u8; 2];
         r.read_exact(&mut ln_data)?;
-        let ln = Self::decode_handshake_len(ln_data)?;
+        let ln = self.decode_handshake_len(ln_data)?;
 
-        let mut handshake_data = [0u8; MAX_HANDSHAKE_LEN];
+        let mut handshake_data = [0u8; MAX_EXT_HANDSHAKE_LEN];
         r.read_exact(&mut handshake_data[..ln])?;
-
-        Self::decode_complete(&mut PacketReader::new(&handshake_data[..ln]))
-            .map_err(|_| NetError::InvalidHandshake)
+        self.decode_handshake(&mut PacketReader::new(&handshake_data[..ln]))
     }
 
-    /// Encode the handshake onto the buffer
-    pub fn to_buf(&self) -> HandshakeBuf {
+    /// Encode the handshake with the length prefix onto the buffer
+    fn to_buf(&self, hshake: &Handshake) -> NetResult<HandshakeBuf> {
+        let mut pw = PacketWriter::with_capacity(MAX_EXT_HANDSHAKE_LEN);
+        self.encode_handshake(hshake, &mut pw)?;
+        let n = pw.len();
+        if n > self.max_len().min(MAX_EXT_HANDSHAKE_LEN) {
+            return Err(NetError::HandshakeSize(n));
+        }
+
         let mut buf = HandshakeBuf::default();
-        let n = self.encode_len();
-        buf.extend(iter::repeat(0).take(n + 2));
-        let mut pw = PacketWriter::new(buf.as_mut());
-        pw.write_u16(n as u16).expect("Handshake len");
-        self.encode(&mut pw).unwrap();
+        buf.extend((n as u16).to_le_bytes());
+        buf.extend(pw.into_inner());
+        Ok(buf)
+    }
+}
+
+impl<F: HandshakeFormat> HandshakeFormat for &F {
+    fn max_len(&self) -> usize {
+        (*self).max_len()
+    }
 
-        buf
+    fn encode_handshake<B: BufMut>(
+        &self,
+        hshake: &Handshake,
+        pw: &mut PacketWriter<B>,
+    ) -> NetResult<()> {
+        (*self).encode_handshake(hshake, pw)
+    }
+
+    fn decode_handshake(&self, pr: &mut PacketReader<'_>) -> NetResult<Handshake> {
+        (*self).decode_handshake(pr)
+    }
+}
+
+/// Default format, which is used by GMS and most other regions:
+/// version, sub version, both IVs and the locale with up to 24 bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultHandshakeFormat;
+
+impl HandshakeFormat for DefaultHandshakeFormat {
+    fn max_len(&self) -> usize {
+        MAX_HANDSHAKE_LEN
+    }
+
+    fn encode_handshake<B: BufMut>(
+        &self,
+        hshake: &Handshake,
+        pw: &mut PacketWriter<B>,
+    ) -> NetResult<()> {
+        if !hshake.extra.is_empty() {
+            return Err(NetError::InvalidHandshake);
+        }
+        Ok(hshake.encode(pw)?)
+    }
+
+    fn decode_handshake(&self, pr: &mut PacketReader<'_>) -> NetResult<Handshake> {
+        Handshake::decode_complete(pr).map_err(|_| NetError::InvalidHandshake)
+    }
+}
+
+/// Extended format, which allows longer handshakes and keeps
+/// the bytes after the locale in `Handshake::extra`.
+/// KMS/JMS style handshakes keep the field order of the default format,
+/// but may send longer patch strings or trailing bytes, which this format covers
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedHandshakeFormat {
+    max_len: usize,
+}
+
+impl Default for ExtendedHandshakeFormat {
+    fn default() -> Self {
+        Self::new(MAX_EXT_HANDSHAKE_LEN)
+    }
+}
+
+impl ExtendedHandshakeFormat {
+    /// Creates the format with the given max length, will panic if
+    /// the length is larger than `MAX_EXT_HANDSHAKE_LEN`
+    pub fn new(max_len: usize) -> Self {
+        assert!(max_len <= MAX_EXT_HANDSHAKE_LEN, "Handshake len");
+        Self { max_len }
+    }
+}
+
+impl HandshakeFormat for ExtendedHandshakeFormat {
+    fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    fn encode_handshake<B: BufMut>(
+        &self,
+        hshake: &Handshake,
+        pw: &mut PacketWriter<B>,
+    ) -> NetResult<()> {
+        hshake.encode(pw)?;
+        pw.write_bytes(&hshake.extra)?;
+        Ok(())
+    }
+
+    fn decode_handshake(&self, pr: &mut PacketReader<'_>) -> NetResult<Handshake> {
+        let mut hshake = Handshake::decode(pr).map_err(|_| NetError::InvalidHandshake)?;
+        hshake.extra = pr
+            .remaining_slice()
+            .try_into()
+            .map_err(|_| NetError::InvalidHandshake)?;
+        Ok(hshake)
     }
 }
 
 pub type HandshakeTuple = (
     u16,
-    ArrayString<2>,
+    ArrayString<MAX_SUB_VERSION_LEN>,
     [u8; ROUND_KEY_LEN],
     [u8; ROUND_KEY_LEN],
     LocaleCode,
@@ -94,6 +236,7 @@ impl From<HandshakeTuple> for Handshake {
             iv_enc: RoundKey::new(value.2),
             iv_dec: RoundKey::new(value.3),
             locale: value.4,
+            extra: ArrayVec::new(),
         }
     }
 }
@@ -113,10 +256,10 @@ packet_wrap!(Handshake<>, HandshakeTuple, HandshakeTuple);
 
 #[cfg(test)]
 mod tests {
-    use crate::codec::legacy::LocaleCode;
+    use crate::{codec::legacy::LocaleCode, NetError};
 
-    use super::Handshake;
-    use arrayvec::ArrayString;
+    use super::{DefaultHandshakeFormat, ExtendedHandshakeFormat, Handshake, HandshakeFormat};
+    use arrayvec::{ArrayString, ArrayVec};
     use shroom_crypto::{RoundKey, ShroomVersion};
     use shroom_pkt::test_util::test_enc_dec;
 
@@ -128,8 +271,47 @@ mod tests {
             iv_enc: RoundKey::new([1u8; 4]),
             iv_dec: RoundKey::new([2u8; 4]),
             locale: LocaleCode::Global,
+            extra: ArrayVec::new(),
         };
 
         test_enc_dec(handshake);
     }
+
+    #[test]
+    fn handshake_formats() -> anyhow::Result<()> {
+        let mut handshake = Handshake {
+            version: ShroomVersion::new(65),
+            sub_version: ArrayString::try_from("1.2.65.1234567")?,
+            iv_enc: RoundKey::new([1u8; 4]),
+            iv_dec: RoundKey::new([2u8; 4]),
+            locale: LocaleCode::Korea,
+            extra: ArrayVec::try_from([7u8, 0, 3].as_slice())?,
+        };
+
+        // Trailing bytes are not supported by the default format
+        assert!(matches!(
+            DefaultHandshakeFormat.to_buf(&handshake),
+            Err(NetError::InvalidHandshake)
+        ));
+
+        let fmt = ExtendedHandshakeFormat::default();
+        let buf = fmt.to_buf(&handshake)?;
+        assert_eq!(fmt.read_handshake(buf.as_slice())?, handshake);
+
+        // Too large for the default format
+        handshake.extra.clear();
+        let buf = fmt.to_buf(&handshake)?;
+        assert_eq!(fmt.read_handshake(buf.as_slice())?, handshake);
+        assert!(matches!(
+            DefaultHandshakeFormat.to_buf(&handshake),
+            Err(NetError::HandshakeSize(27))
+        ));
+
+        let fmt = ExtendedHandshakeFormat::new(16);
+        assert!(matches!(
+            fmt.read_handshake(buf.as_slice()),
+            Err(NetError::HandshakeSize(27))
+        ));
+        Ok(())
+    }
 }
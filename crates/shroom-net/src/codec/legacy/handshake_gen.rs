@@ -1,8 +1,11 @@
-use arrayvec::ArrayString;
+use arrayvec::{ArrayString, ArrayVec};
 
 use shroom_crypto::{RoundKey, ShroomVersion};
 
-use super::{handshake::Handshake, LocaleCode};
+use super::{
+    handshake::{Handshake, MAX_HANDSHAKE_EXTRA_LEN, MAX_SUB_VERSION_LEN},
+    LocaleCode,
+};
 
 /// Handshake generator, to generate a handshake
 pub trait HandshakeGenerator {
@@ -10,24 +13,39 @@ pub trait HandshakeGenerator {
     fn generate_handshake(&self) -> Handshake;
 }
 
+impl<F: Fn() -> Handshake> HandshakeGenerator for F {
+    fn generate_handshake(&self) -> Handshake {
+        self()
+    }
+}
+
 /// Implementation of a very basic Handshake generator
 #[derive(Debug, Clone)]
 pub struct BasicHandshakeGenerator {
     version: ShroomVersion,
-    sub_version: ArrayString<2>,
+    sub_version: ArrayString<MAX_SUB_VERSION_LEN>,
     locale: LocaleCode,
+    extra: ArrayVec<u8, MAX_HANDSHAKE_EXTRA_LEN>,
 }
 
 impl BasicHandshakeGenerator {
-    /// Create a new handshake generator, will panic if subversion is larger than 2
+    /// Create a new handshake generator, will panic if subversion is larger than `MAX_SUB_VERSION_LEN`
     pub fn new(version: ShroomVersion, sub_version: &str, locale: LocaleCode) -> Self {
         Self {
             version,
             sub_version: sub_version.try_into().expect("Subversion"),
             locale,
+            extra: ArrayVec::new(),
         }
     }
 
+    /// Sets the trailing bytes, which require an extended handshake format,
+    /// will panic if extra is larger than `MAX_HANDSHAKE_EXTRA_LEN`
+    pub fn with_extra(mut self, extra: &[u8]) -> Self {
+        self.extra = extra.try_into().expect("Extra");
+        self
+    }
+
     /// Creates a handshake generator
     pub fn global(v: ShroomVersion) -> Self {
         Self::new(v, "1", LocaleCode::Global)
@@ -54,6 +72,7 @@ impl HandshakeGenerator for BasicHandshakeGenerator {
             iv_enc: RoundKey::get_random(&mut rng),
             iv_dec: RoundKey::get_random(&mut rng),
             locale: self.locale,
+            extra: self.extra.clone(),
         }
     }
 }
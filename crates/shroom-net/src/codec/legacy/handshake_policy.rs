@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use arrayvec::ArrayString;
+use shroom_crypto::ShroomVersion;
+
+use crate::{NetError, NetResult};
+
+use super::{
+    handshake::{Handshake, MAX_SUB_VERSION_LEN},
+    LocaleCode,
+};
+
+type HandshakePredicate = Arc<dyn Fn(&Handshake) -> bool + Send + Sync>;
+
+/// Policy, which the client uses to validate the handshake sent by the server
+#[derive(Clone, Default)]
+pub enum HandshakePolicy {
+    /// Accepts every handshake
+    #[default]
+    Any,
+    /// Only accepts the exact version, subversion and locale
+    Exact {
+        version: ShroomVersion,
+        sub_version: ArrayString<MAX_SUB_VERSION_LEN>,
+        locale: LocaleCode,
+    },
+    /// Accepts any of the versions, optionally limited to a locale
+    Versions {
+        versions: Vec<ShroomVersion>,
+        locale: Option<LocaleCode>,
+    },
+    /// Accepts the handshake if the predicate returns true
+    Custom(HandshakePredicate),
+}
+
+impl std::fmt::Debug for HandshakePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Any => write!(f, "Any"),
+            Self::Exact {
+                version,
+                sub_version,
+                locale,
+            } => f
+                .debug_struct("Exact")
+                .field("version", version)
+                .field("sub_version", sub_version)
+                .field("locale", locale)
+                .finish(),
+            Self::Versions { versions, locale } => f
+                .debug_struct("Versions")
+                .field("versions", versions)
+                .field("locale", locale)
+                .finish(),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl HandshakePolicy {
+    /// Only accepts the exact version, subversion and locale,
+    /// will panic if subversion is larger than `MAX_SUB_VERSION_LEN`
+    pub fn exact(version: ShroomVersion, sub_version: &str, locale: LocaleCode) -> Self {
+        Self::Exact {
+            version,
+            sub_version: sub_version.try_into().expect("Subversion"),
+            locale,
+        }
+    }
+
+    /// Accepts any of the versions regardless of the locale
+    pub fn versions(versions: impl IntoIterator<Item = ShroomVersion>) -> Self {
+        Self::Versions {
+            versions: versions.into_iter().collect(),
+            locale: None,
+        }
+    }
+
+    /// Accepts the handshake if the predicate returns true
+    pub fn custom(pred: impl Fn(&Handshake) -> bool + Send + Sync + 'static) -> Self {
+        Self::Custom(Arc::new(pred))
+    }
+
+    /// Checks whether the handshake is accepted by the policy
+    pub fn accepts(&self, hshake: &Handshake) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Exact {
+                version,
+                sub_version,
+                locale,
+            } => {
+                hshake.version == *version
+                    && hshake.sub_version == *sub_version
+                    && hshake.locale == *locale
+            }
+            Self::Versions { versions, locale } => {
+                versions.contains(&hshake.version)
+                    && locale.is_none_or(|locale| hshake.locale == locale)
+            }
+            Self::Custom(pred) => pred(hshake),
+        }
+    }
+
+    /// Validates the handshake, returns `NetError::HandshakeRejected` if It's not accepted
+    pub fn validate(&self, hshake: &Handshake) -> NetResult<()> {
+        if self.accepts(hshake) {
+            Ok(())
+        } else {
+            Err(NetError::HandshakeRejected(Box::new(hshake.clone())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use shroom_crypto::SharedCryptoContext;
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::{
+        codec::{
+            legacy::{
+                handshake_gen::{BasicHandshakeGenerator, HandshakeGenerator},
+                LegacyCodecNoShanda, LocaleCode,
+            },
+            ShroomCodec,
+        },
+        NetError,
+    };
+
+    use super::HandshakePolicy;
+
+    const PORT: u16 = 1738;
+
+    #[test]
+    fn policy() {
+        let hshake = BasicHandshakeGenerator::v95().generate_handshake();
+
+        assert!(HandshakePolicy::Any.accepts(&hshake));
+        assert!(HandshakePolicy::exact(95.into(), "1", LocaleCode::Global).accepts(&hshake));
+        assert!(!HandshakePolicy::exact(95.into(), "2", LocaleCode::Global).accepts(&hshake));
+        assert!(!HandshakePolicy::exact(95.into(), "1", LocaleCode::Europe).accepts(&hshake));
+        assert!(HandshakePolicy::versions([83.into(), 95.into()]).accepts(&hshake));
+        assert!(!HandshakePolicy::versions([83.into()]).accepts(&hshake));
+        assert!(!HandshakePolicy::Versions {
+            versions: vec![95.into()],
+            locale: Some(LocaleCode::Korea)
+        }
+        .accepts(&hshake));
+        assert!(HandshakePolicy::custom(|h| h.version.raw() > 90).accepts(&hshake));
+
+        let err = HandshakePolicy::versions([83.into()])
+            .validate(&hshake)
+            .unwrap_err();
+        assert!(matches!(err, NetError::HandshakeRejected(rejected) if *rejected == hshake));
+    }
+
+    #[test]
+    fn client_rejects_handshake() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            let codec = LegacyCodecNoShanda::<turmoil::net::TcpStream>::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v95(),
+            );
+            loop {
+                let socket = listener.accept().await?.0;
+                let _ = codec.create_server(socket).await?;
+            }
+        });
+
+        sim.client("client", async move {
+            let codec = LegacyCodecNoShanda::<turmoil::net::TcpStream>::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v83(),
+            )
+            .with_handshake_policy(HandshakePolicy::exact(
+                83.into(),
+                "1",
+                LocaleCode::Global,
+            ));
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let Err(NetError::HandshakeRejected(hshake)) = codec.create_client(socket).await else {
+                panic!("handshake must be rejected");
+            };
+            assert_eq!(hshake.version, 95.into());
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+}
@@ -1,27 +1,37 @@
 use futures::Future;
-use shroom_crypto::{net::net_cipher::{NetCipher, CRYPT_AES, CRYPT_ALL}, SharedCryptoContext};
+use shroom_crypto::{
+    net::net_cipher::{CRYPT_AES, CRYPT_ALL},
+    SharedCryptoContext,
+};
 use shroom_pkt::shroom_enum_code;
 use tokio::{
-    io::AsyncWriteExt,
+    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpStream, ToSocketAddrs},
 };
-use tokio_util::codec::{FramedRead, FramedWrite};
 
 use crate::{NetResult, ShroomStream};
 
 use self::{
     codec::{LegacyDecoder, LegacyEncoder},
-    handshake::Handshake,
+    conn::{LegacyConnection, LegacyEvent},
+    framed::{LegacySink, LegacyStream},
+    handshake::{DefaultHandshakeFormat, Handshake, HandshakeFormat},
     handshake_gen::{BasicHandshakeGenerator, HandshakeGenerator},
+    handshake_policy::HandshakePolicy,
 };
 
 use super::{ShroomCodec, ShroomTransport};
 
+pub mod blocking;
 pub mod codec;
+pub mod conn;
+pub mod framed;
 pub mod handshake;
 pub mod handshake_gen;
+pub mod handshake_policy;
 
 pub const MAX_HANDSHAKE_LEN: usize = 24;
+pub const MAX_EXT_HANDSHAKE_LEN: usize = 64;
 pub const MAX_PACKET_LEN: usize = i16::MAX as usize;
 // Locale code for handshake, T means test server
 shroom_enum_code!(
@@ -39,21 +49,31 @@ shroom_enum_code!(
     RlsPe = 10
 );
 
-/// Legacy codec
-pub struct LegacyCodec<const C: u8, T = tokio::net::TcpStream> {
+/// Legacy codec, the server side uses `G` to generate the handshake
+/// and both sides use the format `F` to encode and decode It
+pub struct LegacyCodec<
+    const C: u8,
+    T = tokio::net::TcpStream,
+    G = BasicHandshakeGenerator,
+    F = DefaultHandshakeFormat,
+> {
     crypto_ctx: SharedCryptoContext,
-    handshake_gen: BasicHandshakeGenerator,
+    handshake_gen: G,
+    handshake_fmt: F,
+    handshake_policy: HandshakePolicy,
     _marker: std::marker::PhantomData<T>,
 }
 
 pub type LegacyCodecShanda<T> = LegacyCodec<CRYPT_ALL, T>;
 pub type LegacyCodecNoShanda<T> = LegacyCodec<CRYPT_AES, T>;
 
-impl<const C: u8, T> Clone for LegacyCodec<C, T> {
+impl<const C: u8, T, G: Clone, F: Clone> Clone for LegacyCodec<C, T, G, F> {
     fn clone(&self) -> Self {
         Self {
             crypto_ctx: self.crypto_ctx.clone(),
             handshake_gen: self.handshake_gen.clone(),
+            handshake_fmt: self.handshake_fmt.clone(),
+            handshake_policy: self.handshake_policy.clone(),
             _marker: std::marker::PhantomData,
         }
     }
@@ -68,71 +88,142 @@ impl<const C: u8, T> Default for LegacyCodec<C, T> {
     }
 }
 
-impl<const C: u8, T> LegacyCodec<C, T> {
+impl<const C: u8, T, G> LegacyCodec<C, T, G> {
     /// Creates a new legacy codedc from the crypto context and handshake generator
-    pub fn new(crypto_ctx: SharedCryptoContext, handshake_gen: BasicHandshakeGenerator) -> Self {
+    pub fn new(crypto_ctx: SharedCryptoContext, handshake_gen: G) -> Self {
         Self {
             crypto_ctx,
             handshake_gen,
+            handshake_fmt: DefaultHandshakeFormat,
+            handshake_policy: HandshakePolicy::default(),
             _marker: std::marker::PhantomData,
         }
     }
+}
+
+impl<const C: u8, T, G, F> LegacyCodec<C, T, G, F> {
+    /// Sets the format, which is used to encode and decode the handshake
+    pub fn with_handshake_format<F2>(self, handshake_fmt: F2) -> LegacyCodec<C, T, G, F2> {
+        LegacyCodec {
+            crypto_ctx: self.crypto_ctx,
+            handshake_gen: self.handshake_gen,
+            handshake_fmt,
+            handshake_policy: self.handshake_policy,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the policy, which the client uses to validate the handshake of the server
+    pub fn with_handshake_policy(mut self, handshake_policy: HandshakePolicy) -> Self {
+        self.handshake_policy = handshake_policy;
+        self
+    }
+
+    /// Gets the handshake generator
+    pub fn handshake_gen(&self) -> &G {
+        &self.handshake_gen
+    }
+
+    /// Gets the handshake format
+    pub fn handshake_fmt(&self) -> &F {
+        &self.handshake_fmt
+    }
+
+    /// Gets the handshake policy
+    pub fn handshake_policy(&self) -> &HandshakePolicy {
+        &self.handshake_policy
+    }
 
     /// Creates a new client codec from the given handshake
-    fn create_client_codec(&self, handshake: &Handshake) -> (LegacyEncoder<C>, LegacyDecoder<C>) {
-        let v = handshake.version;
-        (
-            LegacyEncoder::new(NetCipher::new(self.crypto_ctx.clone(), handshake.iv_enc, v)),
-            LegacyDecoder::new(NetCipher::new(
-                self.crypto_ctx.clone(),
-                handshake.iv_dec,
-                v.invert(),
-            )),
-        )
+    pub(crate) fn create_client_codec(
+        &self,
+        handshake: &Handshake,
+    ) -> (LegacyEncoder<C>, LegacyDecoder<C>) {
+        conn::client_codec(&self.crypto_ctx, handshake)
     }
 
     /// Creates a new server codec from the given handshake
-    fn create_server_codec(&self, handshake: &Handshake) -> (LegacyEncoder<C>, LegacyDecoder<C>) {
-        let v = handshake.version;
-        (
-            LegacyEncoder::new(NetCipher::new(
-                self.crypto_ctx.clone(),
-                handshake.iv_dec,
-                v.invert(),
-            )),
-            LegacyDecoder::new(NetCipher::new(self.crypto_ctx.clone(), handshake.iv_enc, v)),
-        )
+    pub(crate) fn create_server_codec(
+        &self,
+        handshake: &Handshake,
+    ) -> (LegacyEncoder<C>, LegacyDecoder<C>) {
+        conn::server_codec(&self.crypto_ctx, handshake)
     }
+}
 
+impl<const C: u8, T, G, F> LegacyCodec<C, T, G, F>
+where
+    T: ShroomTransport + Sync,
+    G: HandshakeGenerator + Send + Sync + Unpin + 'static,
+    F: HandshakeFormat + Send + Sync + Unpin + 'static,
+{
     /// Creates a new client stream, which will read the handshake and then create It
-    async fn create_client_inner(&self, mut trans: T) -> NetResult<ShroomStream<Self>>
-    where
-        T: ShroomTransport + Sync,
-    {
-        let hshake = Handshake::read_handshake_async(&mut trans).await?;
+    async fn create_client_inner(&self, trans: T) -> NetResult<ShroomStream<Self>> {
+        self.create_client_with_handshake(trans)
+            .await
+            .map(|(sess, _)| sess)
+    }
+
+    /// Creates a new client stream and returns It with the handshake received from the server,
+    /// the handshake is validated with the handshake policy
+    pub async fn create_client_with_handshake(
+        &self,
+        mut trans: T,
+    ) -> NetResult<(ShroomStream<Self>, Handshake)> {
+        let mut conn =
+            LegacyConnection::<C, _>::client(self.crypto_ctx.clone(), &self.handshake_fmt);
+        let mut buf = [0; MAX_EXT_HANDSHAKE_LEN + 2];
+        let hshake = loop {
+            if let Some(LegacyEvent::Handshake(hshake)) = conn.poll_event()? {
+                break hshake;
+            }
+            let n = trans.read(&mut buf).await?;
+            if n == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            conn.receive(&buf[..n]);
+        };
+        self.handshake_policy.validate(&hshake)?;
+        Ok((Self::session(trans, conn)?, hshake))
+    }
+
+    /// Creates the session from an established connection, which is driven by the transport
+    fn session(trans: T, conn: LegacyConnection<C, &F>) -> NetResult<ShroomStream<Self>> {
+        let (tx, rx) = conn.split()?;
         let (r, w) = trans.split();
-        let (enc, dec) = self.create_client_codec(&hshake);
-        let r = FramedRead::new(r, dec);
-        let w = FramedWrite::new(w, enc);
-        Ok(ShroomStream::new(w, r))
+        Ok(ShroomStream::new(
+            LegacySink::new(w, tx),
+            LegacyStream::new(r, rx),
+        ))
     }
 
     /// Creates a new server stream, which will send out the handshake
-    async fn create_server_inner(&self, mut trans: T) -> NetResult<ShroomStream<Self>>
-    where
-        T: ShroomTransport + Sync,
-    {
+    async fn create_server_inner(&self, trans: T) -> NetResult<ShroomStream<Self>> {
         let hshake = self.handshake_gen.generate_handshake();
-        trans.write_all(&hshake.to_buf()).await?;
-        let (r, w) = trans.split();
-        let (enc, dec) = self.create_server_codec(&hshake);
-        let r = FramedRead::new(r, dec);
-        let w = FramedWrite::new(w, enc);
-        Ok(ShroomStream::new(w, r))
+        self.create_server_with_handshake(trans, &hshake).await
+    }
+
+    /// Creates a new server stream, which will send out the given handshake
+    pub async fn create_server_with_handshake(
+        &self,
+        mut trans: T,
+        hshake: &Handshake,
+    ) -> NetResult<ShroomStream<Self>> {
+        let mut conn = LegacyConnection::<C, _>::server(
+            self.crypto_ctx.clone(),
+            &self.handshake_fmt,
+            hshake.clone(),
+        )?;
+        trans.write_all(&conn.take_output()).await?;
+        Self::session(trans, conn)
     }
 }
 
-impl<const C: u8> LegacyCodec<C, TcpStream> {
+impl<const C: u8, G, F> LegacyCodec<C, TcpStream, G, F>
+where
+    G: HandshakeGenerator + Send + Sync + Unpin + 'static,
+    F: HandshakeFormat + Send + Sync + Unpin + 'static,
+{
     /// Connects to a server with the given address
     pub async fn connect(&self, addr: impl ToSocketAddrs) -> NetResult<ShroomStream<Self>> {
         let stream = TcpStream::connect(addr).await?;
@@ -145,9 +236,14 @@ impl<const C: u8> LegacyCodec<C, TcpStream> {
     }
 }
 
-impl<const C: u8, T: ShroomTransport + Sync> ShroomCodec for LegacyCodec<C, T> {
-    type Sink = FramedWrite<<Self::Transport as ShroomTransport>::WriteHalf, LegacyEncoder<C>>;
-    type Stream = FramedRead<<Self::Transport as ShroomTransport>::ReadHalf, LegacyDecoder<C>>;
+impl<const C: u8, T, G, F> ShroomCodec for LegacyCodec<C, T, G, F>
+where
+    T: ShroomTransport + Sync,
+    G: HandshakeGenerator + Send + Sync + Unpin + 'static,
+    F: HandshakeFormat + Send + Sync + Unpin + 'static,
+{
+    type Sink = LegacySink<<Self::Transport as ShroomTransport>::WriteHalf, C>;
+    type Stream = LegacyStream<<Self::Transport as ShroomTransport>::ReadHalf, C>;
     type Transport = T;
 
     fn create_client(
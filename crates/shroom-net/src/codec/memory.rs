@@ -0,0 +1,314 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    sync::mpsc,
+};
+
+use crate::{server::ShroomListener, NetResult};
+
+use super::ShroomTransport;
+
+/// Default buffer size of each direction
+pub const DEFAULT_MEMORY_BUF_LEN: usize = 64 * 1024;
+
+/// In-memory transport, which reports the addresses It was created with
+#[derive(Debug)]
+pub struct MemoryTransport {
+    io: DuplexStream,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl MemoryTransport {
+    /// Creates a connected pair of transports, the first one is the client
+    pub fn pair(client_addr: SocketAddr, server_addr: SocketAddr) -> (Self, Self) {
+        Self::pair_with_capacity(client_addr, server_addr, DEFAULT_MEMORY_BUF_LEN)
+    }
+
+    /// Creates a connected pair of transports, which buffer up to `cap` bytes in each direction
+    pub fn pair_with_capacity(
+        client_addr: SocketAddr,
+        server_addr: SocketAddr,
+        cap: usize,
+    ) -> (Self, Self) {
+        let (client, server) = tokio::io::duplex(cap);
+        (
+            Self {
+                io: client,
+                local_addr: client_addr,
+                peer_addr: server_addr,
+            },
+            Self {
+                io: server,
+                local_addr: server_addr,
+                peer_addr: client_addr,
+            },
+        )
+    }
+}
+
+impl AsyncRead for MemoryTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+impl ShroomTransport for MemoryTransport {
+    type ReadHalf = tokio::io::ReadHalf<DuplexStream>;
+    type WriteHalf = tokio::io::WriteHalf<DuplexStream>;
+
+    fn peer_addr(&self) -> NetResult<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn local_addr(&self) -> NetResult<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        tokio::io::split(self.io)
+    }
+}
+
+type Incoming = (MemoryTransport, SocketAddr);
+
+/// In-memory listener, which accepts the transports created by Its connectors
+#[derive(Debug)]
+pub struct MemoryListener {
+    addr: SocketAddr,
+    rx: mpsc::UnboundedReceiver<Incoming>,
+    connector: MemoryConnector,
+}
+
+impl MemoryListener {
+    /// Creates a listener with the given address
+    pub fn bind(addr: SocketAddr) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            addr,
+            rx,
+            connector: MemoryConnector {
+                addr,
+                tx,
+                next_port: Arc::new(AtomicU16::new(49152)),
+                buf_len: DEFAULT_MEMORY_BUF_LEN,
+            },
+        }
+    }
+
+    /// Gets the address of the listener
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Creates a connector, which connects to this listener
+    pub fn connector(&self) -> MemoryConnector {
+        self.connector.clone()
+    }
+
+    /// Accepts the next transport and returns It with the address of the client
+    pub async fn accept(&mut self) -> NetResult<Incoming> {
+        // The listener holds a sender itself, so the channel is never closed
+        Ok(self.rx.recv().await.expect("Memory listener channel"))
+    }
+}
+
+impl ShroomListener for MemoryListener {
+    type Transport = MemoryTransport;
+
+    async fn accept(&mut self) -> NetResult<(Self::Transport, SocketAddr)> {
+        MemoryListener::accept(self).await
+    }
+}
+
+/// Connects transports to a `MemoryListener`
+#[derive(Debug, Clone)]
+pub struct MemoryConnector {
+    addr: SocketAddr,
+    tx: mpsc::UnboundedSender<Incoming>,
+    next_port: Arc<AtomicU16>,
+    buf_len: usize,
+}
+
+impl MemoryConnector {
+    /// Sets the buffer size of each direction for new transports
+    pub fn with_buf_len(mut self, buf_len: usize) -> Self {
+        self.buf_len = buf_len;
+        self
+    }
+
+    /// Gets the address of the listener
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Connects from `127.0.0.1` with an ephemeral port
+    pub fn connect(&self) -> NetResult<MemoryTransport> {
+        let port = self.next_port.fetch_add(1, Ordering::Relaxed);
+        self.connect_from(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+    }
+
+    /// Connects from the given client address,
+    /// fails with `ConnectionRefused` If the listener was dropped
+    pub fn connect_from(&self, client_addr: SocketAddr) -> NetResult<MemoryTransport> {
+        let (client, server) =
+            MemoryTransport::pair_with_capacity(client_addr, self.addr, self.buf_len);
+        self.tx
+            .send((server, client_addr))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, net::SocketAddr};
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::SharedCryptoContext;
+    use shroom_pkt::Packet;
+
+    use crate::{
+        codec::{
+            legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda},
+            websocket::WebSocketCodec,
+            ShroomCodec, ShroomTransport,
+        },
+        server::SessionShutdown,
+        NetError, NetResult, ShroomServer, ShroomStream,
+    };
+
+    use super::{MemoryListener, MemoryTransport};
+
+    fn legacy() -> LegacyCodecNoShanda<MemoryTransport> {
+        LegacyCodecNoShanda::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    fn ws() -> WebSocketCodec<MemoryTransport> {
+        WebSocketCodec::new(http::Uri::from_static("ws://10.0.0.1:8484"))
+    }
+
+    async fn echo<C: ShroomCodec + Unpin>(
+        mut sess: ShroomStream<C>,
+        _shutdown: SessionShutdown,
+    ) -> NetResult<()> {
+        while let Some(Ok(pkt)) = sess.next().await {
+            sess.send(pkt).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_pair() -> anyhow::Result<()> {
+        let client_addr: SocketAddr = "192.168.0.1:56324".parse()?;
+        let server_addr: SocketAddr = "10.0.0.1:8484".parse()?;
+        let (client, server) = MemoryTransport::pair(client_addr, server_addr);
+        assert_eq!(client.local_addr()?, client_addr);
+        assert_eq!(client.peer_addr()?, server_addr);
+        assert_eq!(server.local_addr()?, server_addr);
+        assert_eq!(server.peer_addr()?, client_addr);
+
+        let server = tokio::spawn(async move {
+            let mut sess = legacy().create_server(server).await?;
+            let pkt = sess.next().await.unwrap()?;
+            sess.send(pkt).await?;
+            anyhow::Ok(())
+        });
+
+        let mut sess = legacy().create_client(client).await?;
+        sess.send(Packet::from_static(&[1, 0, 2])).await?;
+        assert_eq!(&sess.next().await.unwrap()?[..], &[1, 0, 2]);
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_listener() -> anyhow::Result<()> {
+        let mut listener = MemoryListener::bind("10.0.0.1:8484".parse()?);
+        let connector = listener.connector();
+
+        let client_addr: SocketAddr = "192.168.0.1:56324".parse()?;
+        let client = connector.connect_from(client_addr)?;
+        assert_eq!(client.peer_addr()?, listener.local_addr());
+        let (server, addr) = listener.accept().await?;
+        assert_eq!(addr, client_addr);
+        assert_eq!(server.peer_addr()?, client_addr);
+
+        // Ephemeral ports are unique
+        let a = connector.connect()?;
+        let b = connector.connect()?;
+        assert_ne!(a.local_addr()?, b.local_addr()?);
+
+        drop(listener);
+        assert!(matches!(
+            connector.connect(),
+            Err(NetError::IO(err)) if err.kind() == ErrorKind::ConnectionRefused
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_server() -> anyhow::Result<()> {
+        let legacy_listener = MemoryListener::bind("10.0.0.1:8484".parse()?);
+        let legacy_conn = legacy_listener.connector();
+        let ws_listener = MemoryListener::bind("10.0.0.1:8485".parse()?);
+        let ws_conn = ws_listener.connector();
+
+        let legacy_server = ShroomServer::new(legacy(), legacy_listener);
+        let legacy_handle = legacy_server.shutdown_handle();
+        let legacy_server = tokio::spawn(legacy_server.run(echo));
+        let ws_server = ShroomServer::new(ws(), ws_listener);
+        let ws_handle = ws_server.shutdown_handle();
+        let ws_server = tokio::spawn(ws_server.run(echo));
+
+        for _ in 0..3 {
+            let mut sess = legacy().create_client(legacy_conn.connect()?).await?;
+            sess.send(Packet::from_static(&[1, 0])).await?;
+            assert_eq!(&sess.next().await.unwrap()?[..], &[1, 0]);
+
+            let mut sess = ws().create_client(ws_conn.connect()?).await?;
+            sess.send(Packet::from_static(&[2, 0])).await?;
+            assert_eq!(&sess.next().await.unwrap()?[..], &[2, 0]);
+        }
+
+        legacy_handle.shutdown();
+        ws_handle.shutdown();
+        legacy_server.await??;
+        ws_server.await??;
+        Ok(())
+    }
+}
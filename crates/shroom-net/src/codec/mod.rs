@@ -1,17 +1,26 @@
 #![allow(non_upper_case_globals)]
 
+pub mod fault;
 pub mod legacy;
+pub mod memory;
+pub mod peek;
+pub mod proxy_protocol;
+pub mod sniff;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod websocket;
 
 use std::pin::Pin;
 
-use futures::{Future, Stream};
-use shroom_pkt::Packet;
+use futures::{Future, Sink, Stream};
+use shroom_pkt::{pkt::EncodeMessage, Packet};
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::{NetError, NetResult, ShroomStream};
-
-
+use crate::{
+    metrics::{self, SharedNetMetrics},
+    timeout::SessionTimeouts,
+    NetError, NetResult, ShroomStream,
+};
 
 pub trait ShroomTransport: AsyncWrite + AsyncRead + Unpin + Send + 'static {
     type ReadHalf: AsyncRead + Unpin + Send + 'static;
@@ -124,9 +133,21 @@ impl ShroomTransport for turmoil::net::TcpStream {
     }
 }
 
+/// Sink, which is able to encode messages directly into Its write buffer
+pub trait ShroomSink: for<'a> Sink<&'a [u8], Error = NetError> {
+    /// Encodes the message, like `start_send` `poll_ready` must be called before
+    fn start_send_msg<T: EncodeMessage>(self: Pin<&mut Self>, msg: T) -> NetResult<()>;
+
+    /// Sends the owned packet, sinks which can use the payload directly
+    /// avoid copying It. `poll_ready` must be called before
+    fn start_send_packet(self: Pin<&mut Self>, pkt: Packet) -> NetResult<()> {
+        Sink::<&[u8]>::start_send(self, &pkt)
+    }
+}
+
 /// Codec trait
 pub trait ShroomCodec: Sized + Unpin + Send + Sync {
-    type Sink: for<'a> futures::Sink<&'a [u8], Error = NetError> + Send + Unpin +  'static;
+    type Sink: ShroomSink + Send + Unpin + 'static;
     type Stream: Stream<Item = Result<Packet, NetError>> + Send + Unpin + 'static;
     type Transport: ShroomTransport;
 
@@ -138,4 +159,76 @@ pub trait ShroomCodec: Sized + Unpin + Send + Sync {
         &self,
         trans: Self::Transport,
     ) -> impl Future<Output = NetResult<ShroomStream<Self>>> + Send;
+
+    /// Creates a client stream with the handshake and read idle timeouts applied,
+    /// the duration of the handshake is recorded in the session stats
+    fn create_client_with_timeouts(
+        &self,
+        trans: Self::Transport,
+        timeouts: SessionTimeouts,
+    ) -> impl Future<Output = NetResult<ShroomStream<Self>>> + Send {
+        async move {
+            let start = tokio::time::Instant::now();
+            let mut sess = timeouts.handshake(self.create_client(trans)).await?;
+            sess.set_handshake_duration(start.elapsed());
+            Ok(sess.with_read_idle_timeout(timeouts.read_idle))
+        }
+    }
+
+    /// Creates a server stream with the handshake and read idle timeouts applied,
+    /// the duration of the handshake is recorded in the session stats
+    fn create_server_with_timeouts(
+        &self,
+        trans: Self::Transport,
+        timeouts: SessionTimeouts,
+    ) -> impl Future<Output = NetResult<ShroomStream<Self>>> + Send {
+        async move {
+            let start = tokio::time::Instant::now();
+            let mut sess = timeouts.handshake(self.create_server(trans)).await?;
+            sess.set_handshake_duration(start.elapsed());
+            Ok(sess.with_read_idle_timeout(timeouts.read_idle))
+        }
+    }
+
+    /// Creates a client stream like `create_client_with_timeouts`,
+    /// the metrics are notified about the handshake, even If It failed
+    fn create_client_with_metrics(
+        &self,
+        trans: Self::Transport,
+        timeouts: SessionTimeouts,
+        metrics: Option<SharedNetMetrics>,
+    ) -> impl Future<Output = NetResult<ShroomStream<Self>>> + Send {
+        async move {
+            match self.create_client_with_timeouts(trans, timeouts).await {
+                Ok(sess) => Ok(sess.with_metrics(metrics)),
+                Err(err) => {
+                    if let Some(metrics) = metrics {
+                        metrics::handshake_failed(metrics.as_ref(), &err);
+                    }
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    /// Creates a server stream like `create_server_with_timeouts`,
+    /// the metrics are notified about the handshake, even If It failed
+    fn create_server_with_metrics(
+        &self,
+        trans: Self::Transport,
+        timeouts: SessionTimeouts,
+        metrics: Option<SharedNetMetrics>,
+    ) -> impl Future<Output = NetResult<ShroomStream<Self>>> + Send {
+        async move {
+            match self.create_server_with_timeouts(trans, timeouts).await {
+                Ok(sess) => Ok(sess.with_metrics(metrics)),
+                Err(err) => {
+                    if let Some(metrics) = metrics {
+                        metrics::handshake_failed(metrics.as_ref(), &err);
+                    }
+                    Err(err)
+                }
+            }
+        }
+    }
 }
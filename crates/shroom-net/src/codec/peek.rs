@@ -0,0 +1,180 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::NetResult;
+
+use super::ShroomTransport;
+
+/// Yields the buffered bytes first and then reads from the inner reader
+fn poll_read_buffered<R: AsyncRead + Unpin>(
+    buffered: &mut BytesMut,
+    inner: &mut R,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+) -> Poll<std::io::Result<()>> {
+    if !buffered.is_empty() {
+        let n = buf.remaining().min(buffered.len());
+        buf.put_slice(&buffered[..n]);
+        buffered.advance(n);
+        return Poll::Ready(Ok(()));
+    }
+
+    Pin::new(inner).poll_read(cx, buf)
+}
+
+/// Transport, which allows to peek at the first bytes sent by the peer,
+/// peeked bytes are still returned by the following reads
+#[derive(Debug)]
+pub struct PeekTransport<T> {
+    inner: T,
+    buf: BytesMut,
+}
+
+impl<T> PeekTransport<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Gets the bytes, which were peeked but not read yet
+    pub fn peeked(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Discards the first `n` peeked bytes,
+    /// will panic if less than `n` bytes were peeked
+    pub fn consume(&mut self, n: usize) {
+        self.buf.advance(n);
+    }
+
+    /// Gets the inner transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> PeekTransport<T> {
+    /// Peeks at the next `n` bytes without consuming them,
+    /// returns less than `n` bytes If the peer closed the connection
+    pub async fn peek(&mut self, n: usize) -> NetResult<&[u8]> {
+        while self.buf.len() < n {
+            self.buf.reserve(n - self.buf.len());
+            if self.inner.read_buf(&mut self.buf).await? == 0 {
+                break;
+            }
+        }
+
+        Ok(&self.buf[..n.min(self.buf.len())])
+    }
+
+    /// Reads the next chunk of at most `max` bytes into the peeked bytes,
+    /// returns 0 If the peer closed the connection
+    pub async fn peek_more(&mut self, max: usize) -> NetResult<usize> {
+        let mut chunk = (&mut self.inner).take(max as u64);
+        self.buf.reserve(max);
+        Ok(chunk.read_buf(&mut self.buf).await?)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for PeekTransport<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        poll_read_buffered(&mut this.buf, &mut this.inner, cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for PeekTransport<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Read half of a `PeekTransport`, which still holds the peeked bytes
+#[derive(Debug)]
+pub struct PeekReadHalf<R> {
+    inner: R,
+    buf: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for PeekReadHalf<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        poll_read_buffered(&mut this.buf, &mut this.inner, cx, buf)
+    }
+}
+
+impl<T: ShroomTransport> ShroomTransport for PeekTransport<T> {
+    type ReadHalf = PeekReadHalf<T::ReadHalf>;
+    type WriteHalf = T::WriteHalf;
+
+    fn peer_addr(&self) -> NetResult<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> NetResult<std::net::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        let (r, w) = self.inner.split();
+        (
+            PeekReadHalf {
+                inner: r,
+                buf: self.buf,
+            },
+            w,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::PeekTransport;
+
+    #[tokio::test]
+    async fn peek() -> anyhow::Result<()> {
+        let (mut client, server) = tokio::io::duplex(64);
+        client.write_all(b"GET / HTTP/1.1").await?;
+        drop(client);
+
+        let mut trans = PeekTransport::new(server);
+        assert_eq!(trans.peek(4).await?, b"GET ");
+        assert_eq!(trans.peek(2).await?, b"GE");
+        // Peeking past the end returns the remaining bytes
+        assert_eq!(trans.peek(64).await?, b"GET / HTTP/1.1");
+
+        let mut data = Vec::new();
+        trans.read_to_end(&mut data).await?;
+        assert_eq!(data, b"GET / HTTP/1.1");
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{NetError, NetResult};
+
+use super::{
+    peek::{PeekReadHalf, PeekTransport},
+    ShroomTransport,
+};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Max length of a v1 header including the CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+/// Whether the PROXY protocol preamble is required
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolMode {
+    /// Rejects connections without a preamble
+    /// or If the preamble isn't received within the timeout
+    Strict(Duration),
+    /// Accepts connections without a preamble. Legacy clients don't send anything
+    /// before the handshake, so the connection is assumed to have no preamble If
+    /// the peer sends nothing within the timeout
+    Optional(Duration),
+}
+
+/// Original addresses of the connection, sent by the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Parses the v1 header line without the CRLF
+fn parse_v1(line: &[u8]) -> NetResult<Option<ProxyHeader>> {
+    let line = std::str::from_utf8(line).map_err(|_| NetError::ProxyProtocol("v1 not utf8"))?;
+    let mut parts = line.split(' ').skip(1);
+    let v6 = match parts.next() {
+        Some("TCP4") => false,
+        Some("TCP6") => true,
+        // Addresses are unknown, so the ones of the connection are used
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(NetError::ProxyProtocol("v1 protocol")),
+    };
+
+    let mut next = || {
+        parts
+            .next()
+            .ok_or(NetError::ProxyProtocol("v1 missing field"))
+    };
+    let src_ip: IpAddr = next()?
+        .parse()
+        .map_err(|_| NetError::ProxyProtocol("v1 address"))?;
+    let dst_ip: IpAddr = next()?
+        .parse()
+        .map_err(|_| NetError::ProxyProtocol("v1 address"))?;
+    let src_port: u16 = next()?
+        .parse()
+        .map_err(|_| NetError::ProxyProtocol("v1 port"))?;
+    let dst_port: u16 = next()?
+        .parse()
+        .map_err(|_| NetError::ProxyProtocol("v1 port"))?;
+    if src_ip.is_ipv6() != v6 || dst_ip.is_ipv6() != v6 {
+        return Err(NetError::ProxyProtocol("v1 address family"));
+    }
+
+    Ok(Some(ProxyHeader {
+        source: SocketAddr::new(src_ip, src_port),
+        destination: SocketAddr::new(dst_ip, dst_port),
+    }))
+}
+
+/// Parses the v2 header, `data` contains the address block after the fixed header
+fn parse_v2(ver_cmd: u8, fam: u8, data: &[u8]) -> NetResult<Option<ProxyHeader>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(NetError::ProxyProtocol("v2 version"));
+    }
+
+    match ver_cmd & 0xF {
+        // Health checks of the proxy use LOCAL, which has no addresses
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(NetError::ProxyProtocol("v2 command")),
+    }
+
+    let port = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+    let header = match fam >> 4 {
+        // INET
+        1 if data.len() >= 12 => {
+            let src = Ipv4Addr::from(<[u8; 4]>::try_from(&data[0..4]).expect("ipv4"));
+            let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&data[4..8]).expect("ipv4"));
+            ProxyHeader {
+                source: SocketAddr::new(src.into(), port(8)),
+                destination: SocketAddr::new(dst.into(), port(10)),
+            }
+        }
+        // INET6
+        2 if data.len() >= 36 => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&data[0..16]).expect("ipv6"));
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&data[16..32]).expect("ipv6"));
+            ProxyHeader {
+                source: SocketAddr::new(src.into(), port(32)),
+                destination: SocketAddr::new(dst.into(), port(34)),
+            }
+        }
+        1 | 2 => return Err(NetError::ProxyProtocol("v2 address length")),
+        // UNSPEC and UNIX addresses can't be reported as socket addresses
+        _ => return Ok(None),
+    };
+    Ok(Some(header))
+}
+
+/// Transport, which parses the PROXY protocol v1 or v2 preamble before the handshake
+/// and reports the original addresses through `peer_addr` and `local_addr`
+#[derive(Debug)]
+pub struct ProxyProtocolTransport<T> {
+    inner: PeekTransport<T>,
+    header: Option<ProxyHeader>,
+}
+
+impl<T: AsyncRead + Unpin> ProxyProtocolTransport<T> {
+    /// Reads the preamble from the transport
+    pub async fn accept(inner: T, mode: ProxyProtocolMode) -> NetResult<Self> {
+        let mut inner = PeekTransport::new(inner);
+        let header = match mode {
+            ProxyProtocolMode::Strict(timeout) => {
+                tokio::time::timeout(timeout, Self::read_header(&mut inner))
+                    .await
+                    .map_err(|_| NetError::ProxyProtocol("preamble timeout"))??
+                    .ok_or(NetError::ProxyProtocol("missing preamble"))?
+            }
+            ProxyProtocolMode::Optional(timeout) => {
+                match tokio::time::timeout(timeout, Self::read_header(&mut inner)).await {
+                    Ok(header) => header?.flatten(),
+                    Err(_) => None,
+                }
+            }
+        };
+
+        Ok(Self { inner, header })
+    }
+
+    /// Reads the header, returns `None` If there's no preamble
+    /// and `Some(None)` If the preamble has no addresses
+    async fn read_header(inner: &mut PeekTransport<T>) -> NetResult<Option<Option<ProxyHeader>>> {
+        let peeked = inner.peek(V2_SIGNATURE.len()).await?;
+        if peeked == V2_SIGNATURE {
+            let hdr = inner.peek(V2_HEADER_LEN).await?;
+            if hdr.len() < V2_HEADER_LEN {
+                return Err(NetError::ProxyProtocol("v2 header truncated"));
+            }
+            let (ver_cmd, fam) = (hdr[12], hdr[13]);
+            let len = V2_HEADER_LEN + u16::from_be_bytes([hdr[14], hdr[15]]) as usize;
+            let data = inner.peek(len).await?;
+            if data.len() < len {
+                return Err(NetError::ProxyProtocol("v2 header truncated"));
+            }
+            let header = parse_v2(ver_cmd, fam, &data[V2_HEADER_LEN..])?;
+            inner.consume(len);
+            return Ok(Some(header));
+        }
+
+        if !peeked.starts_with(V1_PREFIX) {
+            return Ok(None);
+        }
+
+        loop {
+            let peeked = inner.peeked();
+            if let Some(end) = peeked.windows(2).position(|w| w == b"\r\n") {
+                let header = parse_v1(&peeked[..end])?;
+                inner.consume(end + 2);
+                return Ok(Some(header));
+            }
+            if peeked.len() >= V1_MAX_LEN {
+                return Err(NetError::ProxyProtocol("v1 header too long"));
+            }
+            let n = peeked.len() + 1;
+            if inner.peek(n).await?.len() < n {
+                return Err(NetError::ProxyProtocol("v1 header truncated"));
+            }
+        }
+    }
+}
+
+impl<T> ProxyProtocolTransport<T> {
+    /// Gets the header sent by the proxy
+    pub fn header(&self) -> Option<&ProxyHeader> {
+        self.header.as_ref()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ProxyProtocolTransport<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ProxyProtocolTransport<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: ShroomTransport> ShroomTransport for ProxyProtocolTransport<T> {
+    type ReadHalf = PeekReadHalf<T::ReadHalf>;
+    type WriteHalf = T::WriteHalf;
+
+    fn peer_addr(&self) -> NetResult<SocketAddr> {
+        match self.header {
+            Some(header) => Ok(header.source),
+            None => self.inner.peer_addr(),
+        }
+    }
+
+    fn local_addr(&self) -> NetResult<SocketAddr> {
+        match self.header {
+            Some(header) => Ok(header.destination),
+            None => self.inner.local_addr(),
+        }
+    }
+
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        self.inner.split()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::SharedCryptoContext;
+    use shroom_pkt::Packet;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::{
+        codec::{
+            legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda},
+            LocalShroomTransport, ShroomCodec, ShroomTransport,
+        },
+        NetError,
+    };
+
+    use super::{ProxyHeader, ProxyProtocolMode, ProxyProtocolTransport};
+
+    type Transport = LocalShroomTransport<DuplexStream>;
+
+    const STRICT: ProxyProtocolMode = ProxyProtocolMode::Strict(Duration::from_secs(1));
+
+    async fn accept(
+        preamble: &[u8],
+        mode: ProxyProtocolMode,
+    ) -> (
+        Transport,
+        Result<ProxyProtocolTransport<Transport>, NetError>,
+    ) {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(preamble).await.unwrap();
+        let trans = ProxyProtocolTransport::accept(LocalShroomTransport(server), mode).await;
+        (LocalShroomTransport(client), trans)
+    }
+
+    fn header(src: &str, dst: &str) -> Option<ProxyHeader> {
+        Some(ProxyHeader {
+            source: src.parse().unwrap(),
+            destination: dst.parse().unwrap(),
+        })
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (_c, trans) = accept(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 8484\r\n", STRICT).await;
+        let trans = trans.unwrap();
+        assert_eq!(
+            trans.header().copied(),
+            header("192.168.0.1:56324", "10.0.0.1:8484")
+        );
+        assert_eq!(
+            trans.peer_addr().unwrap(),
+            "192.168.0.1:56324".parse().unwrap()
+        );
+        assert_eq!(
+            trans.local_addr().unwrap(),
+            "10.0.0.1:8484".parse().unwrap()
+        );
+
+        let (_c, trans) = accept(b"PROXY UNKNOWN\r\n", STRICT).await;
+        assert_eq!(trans.unwrap().header(), None);
+
+        let (_c, trans) = accept(b"PROXY TCP4 1.2.3.4\r\n", STRICT).await;
+        assert!(matches!(trans, Err(NetError::ProxyProtocol(_))));
+
+        // Address family must match the protocol
+        let (_c, trans) = accept(b"PROXY TCP4 ::1 10.0.0.1 56324 8484\r\n", STRICT).await;
+        assert!(matches!(
+            trans,
+            Err(NetError::ProxyProtocol("v1 address family"))
+        ));
+        let (_c, trans) = accept(b"PROXY TCP6 ::1 10.0.0.1 56324 8484\r\n", STRICT).await;
+        assert!(matches!(
+            trans,
+            Err(NetError::ProxyProtocol("v1 address family"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let mut preamble = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        preamble.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        preamble.extend_from_slice(&[0; 11]);
+        preamble.push(1);
+        preamble.extend_from_slice(&[0; 15]);
+        preamble.push(2);
+        preamble.extend_from_slice(&56324u16.to_be_bytes());
+        preamble.extend_from_slice(&8484u16.to_be_bytes());
+
+        let (_c, trans) = accept(&preamble, STRICT).await;
+        assert_eq!(
+            trans.unwrap().header().copied(),
+            header("[2001:db8::1]:56324", "[::2]:8484")
+        );
+
+        // LOCAL command
+        let (_c, trans) = accept(b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00", STRICT).await;
+        assert_eq!(trans.unwrap().header(), None);
+    }
+
+    #[tokio::test]
+    async fn mode() {
+        // Strict mode requires the preamble
+        let (_c, trans) = accept(b"GET / HTTP/1.1\r\n", STRICT).await;
+        assert!(matches!(trans, Err(NetError::ProxyProtocol(_))));
+
+        // Silent or partial preambles time out in strict mode
+        let strict = ProxyProtocolMode::Strict(Duration::from_millis(10));
+        for preamble in [&b""[..], b"PROXY TCP4"] {
+            let (_c, trans) = accept(preamble, strict).await;
+            assert!(matches!(
+                trans,
+                Err(NetError::ProxyProtocol("preamble timeout"))
+            ));
+        }
+
+        // Data without preamble is passed through
+        let optional = ProxyProtocolMode::Optional(Duration::from_millis(10));
+        let (_c, trans) = accept(b"GET / HTTP/1.1\r\n", optional).await;
+        let mut trans = trans.unwrap();
+        assert_eq!(trans.header(), None);
+        let mut data = [0; 16];
+        trans.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"GET / HTTP/1.1\r\n");
+
+        // Silent legacy clients time out
+        let (_c, trans) = accept(b"", optional).await;
+        assert_eq!(trans.unwrap().header(), None);
+    }
+
+    #[tokio::test]
+    async fn legacy_session() -> anyhow::Result<()> {
+        let (client, trans) =
+            accept(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 8484\r\n", STRICT).await;
+        let trans = trans?;
+        assert_eq!(trans.peer_addr()?, "192.168.0.1:56324".parse()?);
+
+        let server = tokio::spawn(async move {
+            let mut sess = LegacyCodecNoShanda::<ProxyProtocolTransport<Transport>>::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v83(),
+            )
+            .create_server(trans)
+            .await?;
+            let pkt = sess.next().await.unwrap()?;
+            sess.send(pkt).await?;
+            anyhow::Ok(())
+        });
+
+        let mut sess = LegacyCodecNoShanda::<Transport>::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+        .create_client(client)
+        .await?;
+        sess.send(Packet::from_static(&[1, 0, 2])).await?;
+        assert_eq!(&sess.next().await.unwrap()?[..], &[1, 0, 2]);
+        server.await??;
+        Ok(())
+    }
+}
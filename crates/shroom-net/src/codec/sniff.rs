@@ -0,0 +1,313 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use shroom_pkt::{pkt::EncodeMessage, Packet};
+use tokio::net::TcpStream;
+
+use crate::{NetError, NetResult, ShroomStream};
+
+use super::{
+    legacy::{
+        handshake::{DefaultHandshakeFormat, HandshakeFormat},
+        handshake_gen::{BasicHandshakeGenerator, HandshakeGenerator},
+        LegacyCodec,
+    },
+    peek::PeekTransport,
+    websocket::WebSocketCodec,
+    ShroomCodec, ShroomSink, ShroomTransport,
+};
+
+/// Default time to wait for the first bytes of the client
+pub const DEFAULT_SNIFF_TIMEOUT: Duration = Duration::from_millis(250);
+
+const HTTP_GET: &[u8] = b"GET ";
+
+/// Protocol, which was detected for a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SniffProtocol {
+    Legacy,
+    WebSocket,
+}
+
+/// Server codec, which accepts legacy and websocket clients on the same port.
+/// Legacy clients wait silently for the handshake, so If the client sends a
+/// HTTP `GET` within the sniff timeout the websocket path is used, otherwise
+/// the legacy handshake is sent. Clients always use the legacy path
+pub struct SniffCodec<
+    const C: u8,
+    T = TcpStream,
+    G = BasicHandshakeGenerator,
+    F = DefaultHandshakeFormat,
+> {
+    legacy: LegacyCodec<C, PeekTransport<T>, G, F>,
+    ws: WebSocketCodec<PeekTransport<T>>,
+    sniff_timeout: Duration,
+}
+
+impl<const C: u8, T, G, F> SniffCodec<C, T, G, F> {
+    /// Creates a codec from the codecs for both protocols
+    pub fn new(
+        legacy: LegacyCodec<C, PeekTransport<T>, G, F>,
+        ws: WebSocketCodec<PeekTransport<T>>,
+    ) -> Self {
+        Self {
+            legacy,
+            ws,
+            sniff_timeout: DEFAULT_SNIFF_TIMEOUT,
+        }
+    }
+
+    /// Sets the time to wait for the first bytes of the client,
+    /// this delays the handshake for legacy clients
+    pub fn with_sniff_timeout(mut self, timeout: Duration) -> Self {
+        self.sniff_timeout = timeout;
+        self
+    }
+}
+
+/// Sink of either protocol
+pub enum SniffSink<L, W> {
+    Legacy(L),
+    WebSocket(W),
+}
+
+impl<L, W> SniffSink<L, W> {
+    /// Gets the protocol of the sink
+    pub fn protocol(&self) -> SniffProtocol {
+        match self {
+            Self::Legacy(_) => SniffProtocol::Legacy,
+            Self::WebSocket(_) => SniffProtocol::WebSocket,
+        }
+    }
+}
+
+impl<'a, L, W> Sink<&'a [u8]> for SniffSink<L, W>
+where
+    L: ShroomSink + Unpin,
+    W: ShroomSink + Unpin,
+{
+    type Error = NetError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            Self::Legacy(sink) => SinkExt::<&[u8]>::poll_ready_unpin(sink, cx),
+            Self::WebSocket(sink) => SinkExt::<&[u8]>::poll_ready_unpin(sink, cx),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: &'a [u8]) -> Result<(), Self::Error> {
+        match self.get_mut() {
+            Self::Legacy(sink) => sink.start_send_unpin(item),
+            Self::WebSocket(sink) => sink.start_send_unpin(item),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            Self::Legacy(sink) => SinkExt::<&[u8]>::poll_flush_unpin(sink, cx),
+            Self::WebSocket(sink) => SinkExt::<&[u8]>::poll_flush_unpin(sink, cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            Self::Legacy(sink) => SinkExt::<&[u8]>::poll_close_unpin(sink, cx),
+            Self::WebSocket(sink) => SinkExt::<&[u8]>::poll_close_unpin(sink, cx),
+        }
+    }
+}
+
+impl<L, W> ShroomSink for SniffSink<L, W>
+where
+    L: ShroomSink + Unpin,
+    W: ShroomSink + Unpin,
+{
+    fn start_send_msg<M: EncodeMessage>(self: Pin<&mut Self>, msg: M) -> NetResult<()> {
+        match self.get_mut() {
+            Self::Legacy(sink) => Pin::new(sink).start_send_msg(msg),
+            Self::WebSocket(sink) => Pin::new(sink).start_send_msg(msg),
+        }
+    }
+
+    fn start_send_packet(self: Pin<&mut Self>, pkt: Packet) -> NetResult<()> {
+        match self.get_mut() {
+            Self::Legacy(sink) => Pin::new(sink).start_send_packet(pkt),
+            Self::WebSocket(sink) => Pin::new(sink).start_send_packet(pkt),
+        }
+    }
+}
+
+/// Stream of either protocol
+pub enum SniffStream<L, W> {
+    Legacy(L),
+    WebSocket(W),
+}
+
+impl<L, W> Stream for SniffStream<L, W>
+where
+    L: Stream<Item = NetResult<Packet>> + Unpin,
+    W: Stream<Item = NetResult<Packet>> + Unpin,
+{
+    type Item = NetResult<Packet>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            Self::Legacy(stream) => stream.poll_next_unpin(cx),
+            Self::WebSocket(stream) => stream.poll_next_unpin(cx),
+        }
+    }
+}
+
+type LegacySink<const C: u8, T, G, F> =
+    <LegacyCodec<C, PeekTransport<T>, G, F> as ShroomCodec>::Sink;
+type LegacyStream<const C: u8, T, G, F> =
+    <LegacyCodec<C, PeekTransport<T>, G, F> as ShroomCodec>::Stream;
+type WsSink<T> = <WebSocketCodec<PeekTransport<T>> as ShroomCodec>::Sink;
+type WsStream<T> = <WebSocketCodec<PeekTransport<T>> as ShroomCodec>::Stream;
+
+impl<const C: u8, T, G, F> ShroomStream<SniffCodec<C, T, G, F>>
+where
+    T: ShroomTransport + Sync,
+    G: HandshakeGenerator + Send + Sync + Unpin + 'static,
+    F: HandshakeFormat + Send + Sync + Unpin + 'static,
+{
+    /// Gets the protocol, which was detected for this session
+    pub fn protocol(&self) -> SniffProtocol {
+        self.w.protocol()
+    }
+}
+
+impl<const C: u8, T, G, F> SniffCodec<C, T, G, F>
+where
+    T: ShroomTransport + Sync,
+    G: HandshakeGenerator + Send + Sync + Unpin + 'static,
+    F: HandshakeFormat + Send + Sync + Unpin + 'static,
+{
+    /// Waits for the first bytes of the client to detect the protocol
+    async fn sniff(&self, trans: &mut PeekTransport<T>) -> NetResult<SniffProtocol> {
+        let peeked = tokio::time::timeout(self.sniff_timeout, trans.peek(HTTP_GET.len())).await;
+        Ok(match peeked {
+            Ok(Ok(peeked)) if peeked == HTTP_GET => SniffProtocol::WebSocket,
+            Ok(Err(err)) => return Err(err),
+            // Legacy clients don't send anything before the handshake
+            _ => SniffProtocol::Legacy,
+        })
+    }
+
+    fn legacy_stream(
+        sess: ShroomStream<LegacyCodec<C, PeekTransport<T>, G, F>>,
+    ) -> ShroomStream<Self> {
+        let ShroomStream { w, r, .. } = sess;
+        ShroomStream::new(SniffSink::Legacy(w), SniffStream::Legacy(r))
+    }
+
+    fn ws_stream(sess: ShroomStream<WebSocketCodec<PeekTransport<T>>>) -> ShroomStream<Self> {
+        let ShroomStream { w, r, .. } = sess;
+        ShroomStream::new(SniffSink::WebSocket(w), SniffStream::WebSocket(r))
+    }
+}
+
+impl<const C: u8, T, G, F> ShroomCodec for SniffCodec<C, T, G, F>
+where
+    T: ShroomTransport + Sync,
+    G: HandshakeGenerator + Send + Sync + Unpin + 'static,
+    F: HandshakeFormat + Send + Sync + Unpin + 'static,
+{
+    type Sink = SniffSink<LegacySink<C, T, G, F>, WsSink<T>>;
+    type Stream = SniffStream<LegacyStream<C, T, G, F>, WsStream<T>>;
+    type Transport = T;
+
+    async fn create_client(&self, trans: Self::Transport) -> NetResult<ShroomStream<Self>> {
+        let sess = self.legacy.create_client(PeekTransport::new(trans)).await?;
+        Ok(Self::legacy_stream(sess))
+    }
+
+    async fn create_server(&self, trans: Self::Transport) -> NetResult<ShroomStream<Self>> {
+        let mut trans = PeekTransport::new(trans);
+        Ok(match self.sniff(&mut trans).await? {
+            SniffProtocol::Legacy => Self::legacy_stream(self.legacy.create_server(trans).await?),
+            SniffProtocol::WebSocket => Self::ws_stream(self.ws.create_server(trans).await?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::SharedCryptoContext;
+    use shroom_pkt::Packet;
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::codec::{
+        legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda},
+        websocket::WebSocketCodec,
+        ShroomCodec,
+    };
+
+    use super::{SniffCodec, SniffProtocol};
+
+    const PORT: u16 = 1738;
+
+    fn legacy<T>() -> LegacyCodecNoShanda<T> {
+        LegacyCodecNoShanda::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    fn ws<T>() -> WebSocketCodec<T> {
+        WebSocketCodec::new(http::Uri::from_static("ws://server/"))
+    }
+
+    #[test]
+    fn sniff() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            let codec = SniffCodec::new(legacy(), ws());
+            loop {
+                let socket = listener.accept().await?.0;
+                let mut sess = codec.create_server(socket).await?;
+                // Echo the packet with the detected protocol appended
+                let proto = sess.protocol() as u8;
+                while let Some(Ok(pkt)) = sess.next().await {
+                    let mut data = pkt.to_vec();
+                    data.push(proto);
+                    sess.send(data.as_slice()).await?;
+                }
+            }
+        });
+
+        sim.client("legacy", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = legacy().create_client(socket).await?;
+            sess.send(Packet::from_static(&[1, 0])).await?;
+            assert_eq!(
+                &sess.next().await.unwrap()?[..],
+                &[1, 0, SniffProtocol::Legacy as u8]
+            );
+            Ok(())
+        });
+
+        sim.client("ws", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = ws().create_client(socket).await?;
+            sess.send(Packet::from_static(&[1, 0])).await?;
+            assert_eq!(
+                &sess.next().await.unwrap()?[..],
+                &[1, 0, SniffProtocol::WebSocket as u8]
+            );
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::{path::Path, sync::Arc};
+
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{self, crypto::ring, ClientConfig, RootCertStore, ServerConfig};
+
+use crate::NetResult;
+
+use super::ShroomTransport;
+
+pub use tokio_rustls::{
+    client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream, TlsAcceptor,
+    TlsConnector,
+};
+
+/// Implements `ShroomTransport` for a tls stream over a transport,
+/// the addresses are the ones of the underlying transport
+macro_rules! impl_tls_transport {
+    ($($ty:ident),*) => {
+        $(
+            impl<T: ShroomTransport> ShroomTransport for $ty<T> {
+                type ReadHalf = tokio::io::ReadHalf<Self>;
+                type WriteHalf = tokio::io::WriteHalf<Self>;
+
+                fn peer_addr(&self) -> NetResult<std::net::SocketAddr> {
+                    self.get_ref().0.peer_addr()
+                }
+
+                fn local_addr(&self) -> NetResult<std::net::SocketAddr> {
+                    self.get_ref().0.local_addr()
+                }
+
+                fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+                    tokio::io::split(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_tls_transport!(ClientTlsStream, ServerTlsStream);
+
+/// Loads all certificates from the PEM file
+pub fn load_certs(path: impl AsRef<Path>) -> NetResult<Vec<CertificateDer<'static>>> {
+    Ok(CertificateDer::pem_file_iter(path)?.collect::<Result<_, _>>()?)
+}
+
+/// Loads the first private key from the PEM file
+pub fn load_private_key(path: impl AsRef<Path>) -> NetResult<PrivateKeyDer<'static>> {
+    Ok(PrivateKeyDer::from_pem_file(path)?)
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Creates a server config from the certificate chain and private key
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> NetResult<Arc<ServerConfig>> {
+    let cfg = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(cfg))
+}
+
+/// Creates a server config from the certificate chain and private key PEM files
+pub fn server_config_from_pem(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> NetResult<Arc<ServerConfig>> {
+    server_config(load_certs(cert_path)?, load_private_key(key_path)?)
+}
+
+/// Creates a client config, which trusts the root certificates
+pub fn client_config(
+    roots: impl IntoIterator<Item = CertificateDer<'static>>,
+) -> NetResult<Arc<ClientConfig>> {
+    let mut store = RootCertStore::empty();
+    for cert in roots {
+        store.add(cert)?;
+    }
+    let cfg = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    Ok(Arc::new(cfg))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use rustls_pki_types::ServerName;
+    use shroom_crypto::{net::net_cipher::CRYPT_AES, SharedCryptoContext};
+    use shroom_pkt::Packet;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::codec::{
+        legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodec},
+        ShroomCodec, ShroomTransport,
+    };
+
+    use super::{
+        client_config, load_certs, server_config_from_pem, ClientTlsStream, ServerTlsStream,
+        TlsAcceptor, TlsConnector,
+    };
+
+    fn codec<T>() -> LegacyCodec<CRYPT_AES, T> {
+        LegacyCodec::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    #[tokio::test]
+    async fn tls_echo() -> anyhow::Result<()> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let dir = std::env::temp_dir().join(format!("shroom-net-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_path, cert.cert.pem())?;
+        std::fs::write(&key_path, cert.key_pair.serialize_pem())?;
+
+        let acceptor = TlsAcceptor::from(server_config_from_pem(&cert_path, &key_path)?);
+        let connector = TlsConnector::from(client_config(load_certs(&cert_path)?)?);
+        std::fs::remove_dir_all(&dir)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let socket = listener.accept().await?.0;
+            let tls = acceptor.accept(socket).await?;
+            let mut sess = codec::<ServerTlsStream<TcpStream>>()
+                .create_server(tls)
+                .await?;
+            while let Some(Ok(pkt)) = sess.next().await {
+                sess.send(pkt).await?;
+            }
+            anyhow::Ok(())
+        });
+
+        let socket = TcpStream::connect(addr).await?;
+        let local = socket.local_addr()?;
+        let tls = connector
+            .connect(ServerName::try_from("localhost")?, socket)
+            .await?;
+        assert_eq!(ShroomTransport::peer_addr(&tls)?, addr);
+        assert_eq!(ShroomTransport::local_addr(&tls)?, local);
+
+        let mut sess = codec::<ClientTlsStream<TcpStream>>()
+            .create_client(tls)
+            .await?;
+        for data in [&[1, 0][..], &[0xFF; 4096], &[2, 0, 3]] {
+            sess.send(Packet::from_static(data)).await?;
+            assert_eq!(&sess.next().await.unwrap()?[..], data);
+        }
+
+        drop(sess);
+        server.await??;
+        Ok(())
+    }
+}
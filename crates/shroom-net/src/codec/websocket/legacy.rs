@@ -0,0 +1,273 @@
+use std::task::{ready, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use shroom_pkt::{pkt::EncodeMessage, Packet};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    codec::{
+        legacy::{
+            codec::{LegacyDecoder, LegacyEncoder},
+            handshake::{DefaultHandshakeFormat, HandshakeFormat},
+            handshake_gen::{BasicHandshakeGenerator, HandshakeGenerator},
+            LegacyCodec,
+        },
+        peek::PeekTransport,
+        ShroomCodec, ShroomSink, ShroomTransport,
+    },
+    NetError, NetResult, ShroomStream,
+};
+
+use super::{upgrade::WsMetadata, WebSocketCodec, WsCloseReason, WsSink, WsStream};
+
+/// Legacy codec over websocket, every encrypted packet is sent
+/// as one binary frame and the handshake is sent as the first frame
+pub struct LegacyWsCodec<
+    const C: u8,
+    T = TcpStream,
+    G = BasicHandshakeGenerator,
+    F = DefaultHandshakeFormat,
+> {
+    ws: WebSocketCodec<T>,
+    legacy: LegacyCodec<C, T, G, F>,
+}
+
+impl<const C: u8, T, G, F> LegacyWsCodec<C, T, G, F> {
+    /// Creates a new codec, which uses the websocket codec for the upgrade
+    /// and the legacy codec for the handshake and crypto
+    pub fn new(ws: WebSocketCodec<T>, legacy: LegacyCodec<C, T, G, F>) -> Self {
+        Self { ws, legacy }
+    }
+
+    /// Gets the websocket codec
+    pub fn ws(&self) -> &WebSocketCodec<T> {
+        &self.ws
+    }
+
+    /// Gets the legacy codec
+    pub fn legacy(&self) -> &LegacyCodec<C, T, G, F> {
+        &self.legacy
+    }
+}
+
+/// Websocket sink, which encrypts every packet into Its own frame
+pub struct LegacyWsSink<T, const C: u8> {
+    sink: WsSink<T>,
+    enc: LegacyEncoder<C>,
+    buf: BytesMut,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin, const C: u8> LegacyWsSink<T, C> {
+    pub fn new(sink: WsSink<T>, enc: LegacyEncoder<C>) -> Self {
+        Self {
+            sink,
+            enc,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Sends the encrypted packet from the buffer as frame
+    fn start_send_buf(&mut self) -> NetResult<()> {
+        let payload = self.buf.split().freeze();
+        self.sink.start_send_payload(payload)
+    }
+}
+
+impl<'a, T: AsyncRead + AsyncWrite + Unpin, const C: u8> Sink<&'a [u8]> for LegacyWsSink<T, C> {
+    type Error = NetError;
+
+    fn poll_ready(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        SinkExt::<&[u8]>::poll_ready_unpin(&mut self.sink, cx)
+    }
+
+    fn start_send(mut self: std::pin::Pin<&mut Self>, item: &'a [u8]) -> Result<(), Self::Error> {
+        let this = &mut *self;
+        this.enc.encode(item, &mut this.buf)?;
+        this.start_send_buf()
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        SinkExt::<&[u8]>::poll_flush_unpin(&mut self.sink, cx)
+    }
+
+    fn poll_close(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        SinkExt::<&[u8]>::poll_close_unpin(&mut self.sink, cx)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin, const C: u8> ShroomSink for LegacyWsSink<T, C> {
+    fn start_send_msg<M: EncodeMessage>(
+        mut self: std::pin::Pin<&mut Self>,
+        msg: M,
+    ) -> NetResult<()> {
+        let this = &mut *self;
+        this.enc.encode_msg(msg, &mut this.buf)?;
+        this.start_send_buf()
+    }
+}
+
+/// Websocket stream, which decrypts every frame into exactly one packet
+pub struct LegacyWsStream<T, const C: u8> {
+    stream: WsStream<T>,
+    dec: LegacyDecoder<C>,
+}
+
+impl<T, const C: u8> LegacyWsStream<T, C> {
+    pub fn new(stream: WsStream<T>, dec: LegacyDecoder<C>) -> Self {
+        Self { stream, dec }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin, const C: u8> Stream for LegacyWsStream<T, C> {
+    type Item = NetResult<Packet>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let frame = match ready!(self.stream.poll_next_unpin(cx)) {
+            Some(Ok(frame)) => frame,
+            res => return Poll::Ready(res),
+        };
+
+        let mut buf = BytesMut::from(Bytes::from(frame));
+        let len = buf.len();
+        Poll::Ready(Some(match self.dec.decode(&mut buf)? {
+            Some(pkt) if buf.is_empty() => Ok(pkt),
+            _ => Err(NetError::WebsocketFrame(len)),
+        }))
+    }
+}
+
+impl<const C: u8, T, G, F> ShroomStream<LegacyWsCodec<C, T, G, F>>
+where
+    T: ShroomTransport + Sync,
+    G: HandshakeGenerator + Send + Sync + Unpin + 'static,
+    F: HandshakeFormat + Send + Sync + Unpin + 'static,
+{
+    /// Gets the metadata of the upgrade
+    pub fn ws_metadata(&self) -> &WsMetadata {
+        self.r.stream.metadata()
+    }
+
+    /// Gets the close reason, If the peer closed the connection
+    pub fn close_reason(&self) -> Option<&WsCloseReason> {
+        self.r.stream.close_reason()
+    }
+}
+
+impl<const C: u8, T, G, F> ShroomCodec for LegacyWsCodec<C, T, G, F>
+where
+    T: ShroomTransport + Sync,
+    G: HandshakeGenerator + Send + Sync + Unpin + 'static,
+    F: HandshakeFormat + Send + Sync + Unpin + 'static,
+{
+    type Sink = LegacyWsSink<PeekTransport<T>, C>;
+    type Stream = LegacyWsStream<PeekTransport<T>, C>;
+    type Transport = T;
+
+    async fn create_client(&self, trans: Self::Transport) -> NetResult<ShroomStream<Self>> {
+        let (w, mut r) = self.ws.connect_ws(trans).await?;
+        let frame = r
+            .next()
+            .await
+            .ok_or(NetError::IO(std::io::ErrorKind::UnexpectedEof.into()))??;
+        let hshake = self.legacy.handshake_fmt().read_handshake(&frame[..])?;
+        self.legacy.handshake_policy().validate(&hshake)?;
+
+        let (enc, dec) = self.legacy.create_client_codec(&hshake);
+        Ok(ShroomStream::new(
+            LegacyWsSink::new(w, enc),
+            LegacyWsStream::new(r, dec),
+        ))
+    }
+
+    async fn create_server(&self, trans: Self::Transport) -> NetResult<ShroomStream<Self>> {
+        let (mut w, r) = self.ws.accept_ws(trans).await?;
+        let hshake = self.legacy.handshake_gen().generate_handshake();
+        let buf = self.legacy.handshake_fmt().to_buf(&hshake)?;
+        SinkExt::<&[u8]>::send(&mut w, &buf[..]).await?;
+
+        let (enc, dec) = self.legacy.create_server_codec(&hshake);
+        Ok(ShroomStream::new(
+            LegacyWsSink::new(w, enc),
+            LegacyWsStream::new(r, dec),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::{net::net_cipher::CRYPT_ALL, SharedCryptoContext};
+    use shroom_pkt::Packet;
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::codec::{
+        legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodec},
+        websocket::WebSocketCodec,
+        ShroomCodec,
+    };
+
+    use super::LegacyWsCodec;
+
+    const PORT: u16 = 1738;
+
+    fn codec() -> LegacyWsCodec<CRYPT_ALL, TcpStream> {
+        LegacyWsCodec::new(
+            WebSocketCodec::new(http::Uri::from_static("ws://server/")),
+            LegacyCodec::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v83(),
+            ),
+        )
+    }
+
+    #[test]
+    fn echo() -> anyhow::Result<()> {
+        const ECHO_DATA: [&[u8]; 4] = [&[1, 0], &[0xFF; 4096], &[2, 0, 1, 2], &[0x0; 1024]];
+
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            let codec = codec();
+            loop {
+                let socket = listener.accept().await?.0;
+                let mut sess = codec.create_server(socket).await?;
+                while let Some(Ok(pkt)) = sess.next().await {
+                    sess.send(pkt).await?;
+                }
+            }
+        });
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            for data in ECHO_DATA {
+                sess.send(Packet::from_static(data)).await?;
+                assert_eq!(&sess.next().await.unwrap()?[..], data);
+            }
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+}
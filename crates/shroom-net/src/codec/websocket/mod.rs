@@ -0,0 +1,654 @@
+use std::task::{ready, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use http::{HeaderMap, HeaderName, HeaderValue};
+use shroom_pkt::{pkt::EncodeMessage, Packet};
+use tokio::{io::AsyncRead, io::AsyncWrite};
+use tokio_websockets::{CloseCode, Message, WebSocketStream};
+
+use crate::{NetError, NetResult, ShroomStream};
+
+use self::upgrade::{header_tokens, WsAcceptor, WsMetadata, PROTOCOL_HEADER};
+
+use super::{peek::PeekTransport, ShroomCodec, ShroomSink, ShroomTransport};
+
+pub mod legacy;
+pub mod upgrade;
+
+pub struct WebSocketCodec<T> {
+    uri: http::Uri,
+    headers: HeaderMap,
+    protocols: Vec<String>,
+    acceptor: Option<WsAcceptor>,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T> WebSocketCodec<T> {
+    pub fn new(uri: http::Uri) -> Self {
+        Self {
+            uri,
+            headers: HeaderMap::new(),
+            protocols: Vec::new(),
+            acceptor: None,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Adds a header, which the client sends with the upgrade request
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Adds an `Authorization` header with the bearer token,
+    /// will panic if the token is not a valid header value
+    pub fn with_bearer_token(self, token: &str) -> Self {
+        let value = HeaderValue::try_from(format!("Bearer {token}")).expect("Bearer token");
+        self.with_header(http::header::AUTHORIZATION, value)
+    }
+
+    /// Sets the subprotocols, the client offers all of them while the server
+    /// selects the first one, which was offered by the client.
+    /// Will panic if the protocols are not a valid header value
+    pub fn with_protocols(
+        mut self,
+        protocols: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.protocols = protocols.into_iter().map(Into::into).collect();
+        if self.protocols.is_empty() {
+            self.headers.remove(PROTOCOL_HEADER);
+            return self;
+        }
+        let value = HeaderValue::try_from(self.protocols.join(", ")).expect("Subprotocols");
+        self.with_header(HeaderName::from_static(PROTOCOL_HEADER), value)
+    }
+
+    /// Sets the acceptor, which the server uses to accept or reject the upgrade request
+    /// based on the path, origin or auth headers, before the session is created
+    pub fn with_acceptor(
+        mut self,
+        acceptor: impl Fn(&http::Request<()>) -> Result<(), http::StatusCode> + Send + Sync + 'static,
+    ) -> Self {
+        self.acceptor = Some(std::sync::Arc::new(acceptor));
+        self
+    }
+}
+
+/// Websocket sink, owned `Bytes` and `Packet`s are used as frame payload
+/// directly while borrowed slices have to be copied once
+pub struct WsSink<T> {
+    sink: SplitSink<WebSocketStream<T>, tokio_websockets::Message>,
+    buf: BytesMut,
+    close_frame: WsCloseReason,
+    close_sent: bool,
+}
+
+impl<T> WsSink<T> {
+    pub fn new(sink: SplitSink<WebSocketStream<T>, tokio_websockets::Message>) -> Self {
+        Self {
+            sink,
+            buf: BytesMut::new(),
+            close_frame: WsCloseReason::default(),
+            close_sent: false,
+        }
+    }
+
+    /// Sets the code and reason of the close frame, which is sent when the sink is closed
+    pub fn set_close_frame(&mut self, code: CloseCode, reason: &str) {
+        self.close_frame = WsCloseReason {
+            code,
+            reason: reason.to_string(),
+        };
+    }
+
+    /// Creates a message by copying the item into the buffer
+    pub fn create_msg(&mut self, item: &[u8]) -> tokio_websockets::Message {
+        self.buf.extend_from_slice(item);
+        tokio_websockets::Message::binary(self.buf.split().freeze())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> WsSink<T> {
+    /// Sends the payload without copying It
+    fn start_send_payload(&mut self, payload: Bytes) -> NetResult<()> {
+        self.sink
+            .start_send_unpin(tokio_websockets::Message::binary(payload))
+            .map_err(|err| err.into())
+    }
+}
+
+impl<'a, T: AsyncRead + AsyncWrite + Unpin> Sink<&'a [u8]> for WsSink<T> {
+    type Error = NetError;
+
+    fn poll_ready(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.sink.poll_ready_unpin(cx).map_err(|err| err.into())
+    }
+
+    fn start_send(mut self: std::pin::Pin<&mut Self>, item: &'a [u8]) -> Result<(), Self::Error> {
+        let msg = self.create_msg(item);
+        self.sink.start_send_unpin(msg).map_err(|err| err.into())
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.sink.poll_flush_unpin(cx).map_err(|err| err.into())
+    }
+
+    fn poll_close(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        if !self.close_sent {
+            ready!(self.sink.poll_ready_unpin(cx))?;
+            let msg = Message::close(Some(self.close_frame.code), &self.close_frame.reason);
+            self.sink.start_send_unpin(msg)?;
+            self.close_sent = true;
+        }
+
+        match ready!(self.sink.poll_close_unpin(cx)) {
+            // The peer closed the connection first, so our close frame was discarded
+            Err(tokio_websockets::Error::AlreadyClosed) => {
+                self.sink.poll_close_unpin(cx).map_err(|err| err.into())
+            }
+            res => Poll::Ready(res.map_err(|err| err.into())),
+        }
+    }
+}
+
+/// Implements `Sink` for owned payloads, which are used as frame payload directly
+macro_rules! impl_owned_ws_sink {
+    ($($ty:ty),*) => {
+        $(
+            impl<T: AsyncRead + AsyncWrite + Unpin> Sink<$ty> for WsSink<T> {
+                type Error = NetError;
+
+                fn poll_ready(
+                    self: std::pin::Pin<&mut Self>,
+                    cx: &mut std::task::Context<'_>,
+                ) -> std::task::Poll<Result<(), Self::Error>> {
+                    Sink::<&[u8]>::poll_ready(self, cx)
+                }
+
+                fn start_send(mut self: std::pin::Pin<&mut Self>, item: $ty) -> Result<(), Self::Error> {
+                    self.start_send_payload(item.into())
+                }
+
+                fn poll_flush(
+                    self: std::pin::Pin<&mut Self>,
+                    cx: &mut std::task::Context<'_>,
+                ) -> std::task::Poll<Result<(), Self::Error>> {
+                    Sink::<&[u8]>::poll_flush(self, cx)
+                }
+
+                fn poll_close(
+                    self: std::pin::Pin<&mut Self>,
+                    cx: &mut std::task::Context<'_>,
+                ) -> std::task::Poll<Result<(), Self::Error>> {
+                    Sink::<&[u8]>::poll_close(self, cx)
+                }
+            }
+        )*
+    };
+}
+
+impl_owned_ws_sink!(Bytes, Packet);
+
+impl<T: AsyncRead + AsyncWrite + Unpin> ShroomSink for WsSink<T> {
+    fn start_send_msg<M: EncodeMessage>(
+        mut self: std::pin::Pin<&mut Self>,
+        msg: M,
+    ) -> NetResult<()> {
+        // Encode directly into the buffer, which is then frozen into the payload,
+        // a partly encoded message must not end up in the next frame
+        let start = self.buf.len();
+        if let Err(err) = msg.encode_message(&mut self.buf) {
+            self.buf.truncate(start);
+            return Err(err.into());
+        }
+        let payload = self.buf.split().freeze();
+        self.start_send_payload(payload)
+    }
+
+    fn start_send_packet(mut self: std::pin::Pin<&mut Self>, pkt: Packet) -> NetResult<()> {
+        self.start_send_payload(pkt.into())
+    }
+}
+
+/// Close code and reason of a websocket close frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsCloseReason {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+impl Default for WsCloseReason {
+    fn default() -> Self {
+        Self {
+            code: CloseCode::NORMAL_CLOSURE,
+            reason: String::new(),
+        }
+    }
+}
+
+/// Websocket stream, which only yields binary frames as packets.
+/// Pings are answered, a close frame ends the stream and text frames are rejected
+pub struct WsStream<T> {
+    stream: SplitStream<WebSocketStream<T>>,
+    close_reason: Option<WsCloseReason>,
+    metadata: WsMetadata,
+}
+
+impl<T> WsStream<T> {
+    pub fn new(stream: SplitStream<WebSocketStream<T>>) -> Self {
+        Self::with_metadata(stream, WsMetadata::default())
+    }
+
+    /// Creates the stream with the metadata of the upgrade
+    pub fn with_metadata(stream: SplitStream<WebSocketStream<T>>, metadata: WsMetadata) -> Self {
+        Self {
+            stream,
+            close_reason: None,
+            metadata,
+        }
+    }
+
+    /// Gets the metadata of the upgrade
+    pub fn metadata(&self) -> &WsMetadata {
+        &self.metadata
+    }
+
+    /// Gets the close reason, If the peer closed the connection
+    pub fn close_reason(&self) -> Option<&WsCloseReason> {
+        self.close_reason.as_ref()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for WsStream<T> {
+    type Item = Result<Packet, NetError>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        loop {
+            let msg = match ready!(self.stream.poll_next_unpin(cx)) {
+                Some(Ok(msg)) => msg,
+                Some(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
+                None => return Poll::Ready(None),
+            };
+
+            if msg.is_binary() {
+                let data: Bytes = msg.into_payload().into();
+                return Poll::Ready(Some(Ok(Packet::from(data))));
+            }
+
+            if msg.is_text() {
+                return Poll::Ready(Some(Err(NetError::WebsocketText)));
+            }
+
+            // The stream acknowledges the close frame and ends on the next poll
+            if let Some((code, reason)) = msg.as_close() {
+                self.close_reason = Some(WsCloseReason {
+                    code,
+                    reason: reason.to_string(),
+                });
+            }
+            // Pongs are queued by the websocket stream and flushed on the next poll
+        }
+    }
+}
+
+impl<T: ShroomTransport + Sync> ShroomStream<WebSocketCodec<T>> {
+    /// Gets the metadata of the upgrade
+    pub fn ws_metadata(&self) -> &WsMetadata {
+        self.r.metadata()
+    }
+
+    /// Gets the close reason, If the peer closed the connection
+    pub fn close_reason(&self) -> Option<&WsCloseReason> {
+        self.r.close_reason()
+    }
+
+    /// Closes the session with the close code and reason
+    pub async fn close_with(mut self, code: CloseCode, reason: &str) -> NetResult<()> {
+        self.w.set_close_frame(code, reason);
+        self.close().await
+    }
+}
+
+impl<T: ShroomTransport> WebSocketCodec<T> {
+    /// Performs the client side of the upgrade and splits the websocket
+    pub(crate) async fn connect_ws(
+        &self,
+        trans: T,
+    ) -> NetResult<(WsSink<PeekTransport<T>>, WsStream<PeekTransport<T>>)> {
+        let mut builder = tokio_websockets::ClientBuilder::from_uri(self.uri.clone());
+        for (name, value) in self.headers.iter() {
+            builder = builder.add_header(name.clone(), value.clone());
+        }
+        let (ws, resp) = builder.connect_on(PeekTransport::new(trans)).await?;
+        let (parts, _) = resp.into_parts();
+        let protocol = parts
+            .headers
+            .get(PROTOCOL_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| header_tokens(v).next())
+            .map(str::to_string);
+        let metadata = WsMetadata {
+            uri: self.uri.clone(),
+            headers: parts.headers,
+            protocol,
+        };
+
+        let (w, r) = ws.split();
+        Ok((WsSink::new(w), WsStream::with_metadata(r, metadata)))
+    }
+
+    /// Performs the server side of the upgrade and splits the websocket,
+    /// the transport keeps any bytes the client sent after the upgrade request
+    pub(crate) async fn accept_ws(
+        &self,
+        trans: T,
+    ) -> NetResult<(WsSink<PeekTransport<T>>, WsStream<PeekTransport<T>>)> {
+        let mut trans = PeekTransport::new(trans);
+        let metadata = upgrade::accept(&mut trans, &self.protocols, self.acceptor.as_ref()).await?;
+        let ws = tokio_websockets::ServerBuilder::new().serve(trans);
+        let (w, r) = ws.split();
+        Ok((WsSink::new(w), WsStream::with_metadata(r, metadata)))
+    }
+}
+
+impl<T: ShroomTransport + Sync> ShroomCodec for WebSocketCodec<T> {
+    type Stream = WsStream<PeekTransport<Self::Transport>>;
+    type Sink = WsSink<PeekTransport<Self::Transport>>;
+    type Transport = T;
+
+    async fn create_client(
+        &self,
+        trans: Self::Transport,
+    ) -> crate::NetResult<crate::ShroomStream<Self>> {
+        let (w, r) = self.connect_ws(trans).await?;
+        Ok(crate::ShroomStream::new(w, r))
+    }
+
+    async fn create_server(
+        &self,
+        trans: Self::Transport,
+    ) -> crate::NetResult<crate::ShroomStream<Self>> {
+        let (w, r) = self.accept_ws(trans).await?;
+        Ok(crate::ShroomStream::new(w, r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use bytes::{BufMut, Bytes};
+    use futures::{SinkExt, StreamExt};
+    use http::{header::ORIGIN, HeaderValue, StatusCode};
+    use shroom_pkt::{pkt::EncodeMessage, Packet};
+    use tokio_websockets::{upgrade::Error as UpgradeError, CloseCode, Message};
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::{
+        codec::{memory::MemoryTransport, ShroomCodec},
+        NetError,
+    };
+
+    use super::{WebSocketCodec, WsCloseReason};
+
+    const PORT: u16 = 1738;
+
+    fn codec() -> WebSocketCodec<TcpStream> {
+        WebSocketCodec::new(http::Uri::from_static("ws://127.0.0.1"))
+    }
+
+    async fn bind() -> std::io::Result<TcpListener> {
+        TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await
+    }
+
+    /// Runs a plain websocket server, which sends the messages to every client
+    fn plain_server(sim: &mut turmoil::Sim<'_>, msgs: fn() -> Vec<Message>) {
+        sim.host("server", move || async move {
+            let listener = bind().await?;
+            loop {
+                let socket = listener.accept().await?.0;
+                let mut ws = tokio_websockets::ServerBuilder::new()
+                    .accept(socket)
+                    .await?;
+                for msg in msgs() {
+                    ws.send(msg).await?;
+                }
+                // Echo pongs back as binary frames
+                while let Some(Ok(msg)) = ws.next().await {
+                    if msg.is_pong() {
+                        ws.send(Message::binary(msg.into_payload())).await?;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Message, which writes the opcode and then fails
+    struct FailingMsg;
+
+    impl EncodeMessage for FailingMsg {
+        fn encode_message<B: BufMut>(self, mut buf: B) -> Result<(), shroom_pkt::Error> {
+            buf.put_u16_le(0x10);
+            Err(shroom_pkt::Error::OutOfCapacity)
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_msg() -> anyhow::Result<()> {
+        let (client, server) =
+            MemoryTransport::pair("192.168.0.1:56324".parse()?, "10.0.0.1:8484".parse()?);
+        let codec = WebSocketCodec::<MemoryTransport>::new(http::Uri::from_static("ws://10.0.0.1"));
+        let (mut client, mut server) =
+            tokio::try_join!(codec.create_client(client), codec.create_server(server))?;
+
+        assert!(client.send_msg(FailingMsg).await.is_err());
+        client.send(Packet::from_static(&[1, 0])).await?;
+        assert_eq!(&server.next().await.unwrap()?[..], &[1, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn ping() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+        plain_server(&mut sim, || vec![Message::ping("ping")]);
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            // The ping is answered with a pong, which is echoed back
+            assert_eq!(&sess.next().await.unwrap()?[..], b"ping");
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn peer_close() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+        plain_server(&mut sim, || {
+            vec![
+                Message::binary(&b"\x01\x00"[..]),
+                Message::close(Some(CloseCode::GOING_AWAY), "bye"),
+            ]
+        });
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            assert_eq!(&sess.next().await.unwrap()?[..], &[1, 0]);
+            assert!(sess.next().await.is_none());
+            assert_eq!(
+                sess.close_reason(),
+                Some(&WsCloseReason {
+                    code: CloseCode::GOING_AWAY,
+                    reason: "bye".to_string()
+                })
+            );
+            sess.close().await?;
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn text_rejected() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+        plain_server(&mut sim, || vec![Message::text("text")]);
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            assert!(matches!(
+                sess.next().await,
+                Some(Err(NetError::WebsocketText))
+            ));
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn close_frame() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = bind().await?;
+            loop {
+                let socket = listener.accept().await?.0;
+                let sess = codec().create_server(socket).await?;
+                sess.close_with(CloseCode::SERVICE_RESTART, "restart")
+                    .await?;
+            }
+        });
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let (mut ws, _) =
+                tokio_websockets::ClientBuilder::from_uri(http::Uri::from_static("ws://127.0.0.1"))
+                    .connect_on(socket)
+                    .await?;
+            let msg = ws.next().await.unwrap()?;
+            assert_eq!(
+                msg.as_close(),
+                Some((CloseCode::SERVICE_RESTART, "restart"))
+            );
+            // The close is acknowledged and the stream ends
+            assert!(ws.next().await.is_none());
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+
+    fn game_codec() -> WebSocketCodec<TcpStream> {
+        codec()
+            .with_protocols(["shroom.v2", "shroom.v1"])
+            .with_acceptor(|req| {
+                let headers = req.headers();
+                if req.uri().path() != "/game" {
+                    return Err(StatusCode::NOT_FOUND);
+                }
+                if headers.get(ORIGIN).is_none_or(|v| v != "https://shroom.rs") {
+                    return Err(StatusCode::FORBIDDEN);
+                }
+                if headers
+                    .get(http::header::AUTHORIZATION)
+                    .is_none_or(|v| v != "Bearer secret")
+                {
+                    return Err(StatusCode::UNAUTHORIZED);
+                }
+                Ok(())
+            })
+    }
+
+    #[test]
+    fn upgrade() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = bind().await?;
+            let codec = game_codec();
+            loop {
+                let socket = listener.accept().await?.0;
+                let Ok(mut sess) = codec.create_server(socket).await else {
+                    continue;
+                };
+                // Send the negotiated protocol back
+                let meta = sess.ws_metadata();
+                assert_eq!(meta.uri.path(), "/game");
+                let proto = Packet::from(Bytes::from(meta.protocol.clone().unwrap_or_default()));
+                sess.send_packet(proto).await?;
+            }
+        });
+
+        sim.client("client", async move {
+            let client = || {
+                WebSocketCodec::<TcpStream>::new(http::Uri::from_static("ws://server/game"))
+                    .with_header(ORIGIN, HeaderValue::from_static("https://shroom.rs"))
+                    .with_protocols(["shroom.v1", "shroom.v3"])
+            };
+
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = client()
+                .with_bearer_token("secret")
+                .create_client(socket)
+                .await?;
+            assert_eq!(sess.ws_metadata().protocol.as_deref(), Some("shroom.v1"));
+            assert_eq!(&sess.next().await.unwrap()?[..], b"shroom.v1");
+
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let err = client()
+                .with_bearer_token("wrong")
+                .create_client(socket)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                NetError::Websocket(tokio_websockets::Error::Upgrade(
+                    UpgradeError::DidNotSwitchProtocols(401)
+                ))
+            ));
+
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let err = client()
+                .with_header(ORIGIN, HeaderValue::from_static("https://evil.com"))
+                .with_bearer_token("secret")
+                .create_client(socket)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                NetError::Websocket(tokio_websockets::Error::Upgrade(
+                    UpgradeError::DidNotSwitchProtocols(403)
+                ))
+            ));
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+}
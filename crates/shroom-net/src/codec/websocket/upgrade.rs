@@ -0,0 +1,282 @@
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_websockets::upgrade::Error as UpgradeError;
+
+use crate::{codec::peek::PeekTransport, NetError, NetResult};
+
+/// Max length of an upgrade request
+pub const MAX_REQUEST_LEN: usize = 8 * 1024;
+const REQUEST_CHUNK_LEN: usize = 1024;
+const MAX_REQUEST_HEADERS: usize = 64;
+const WS_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const PROTOCOL_HEADER: &str = "sec-websocket-protocol";
+
+/// Accepts the upgrade request or rejects It with the status code
+pub type WsAcceptor = Arc<dyn Fn(&Request<()>) -> Result<(), StatusCode> + Send + Sync>;
+
+/// Metadata of an upgraded websocket session
+#[derive(Debug, Clone, Default)]
+pub struct WsMetadata {
+    /// Uri of the upgrade request
+    pub uri: http::Uri,
+    /// Headers of the upgrade request on the server and of the response on the client
+    pub headers: HeaderMap,
+    /// Negotiated subprotocol
+    pub protocol: Option<String>,
+}
+
+fn upgrade_err(err: UpgradeError) -> NetError {
+    tokio_websockets::Error::Upgrade(err).into()
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> NetResult<&'a str> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .ok_or(upgrade_err(UpgradeError::MissingHeader(name)))
+}
+
+/// Splits a comma separated header value into Its trimmed tokens
+pub fn header_tokens(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Builds the request from the parsed request line and headers
+fn build_request(req: &httparse::Request<'_, '_>) -> NetResult<Request<()>> {
+    let method = req
+        .method
+        .ok_or(upgrade_err(UpgradeError::Parsing(httparse::Error::Token)))?;
+    let mut request = Request::builder()
+        .method(method)
+        .uri(req.path.unwrap_or("/"))
+        .body(())
+        .map_err(|_| upgrade_err(UpgradeError::Parsing(httparse::Error::Token)))?;
+    for hdr in req.headers.iter() {
+        let name = HeaderName::from_bytes(hdr.name.as_bytes())
+            .map_err(|_| upgrade_err(UpgradeError::Parsing(httparse::Error::HeaderName)))?;
+        let value = HeaderValue::from_bytes(hdr.value)
+            .map_err(|_| upgrade_err(UpgradeError::Parsing(httparse::Error::HeaderValue)))?;
+        request.headers_mut().append(name, value);
+    }
+    Ok(request)
+}
+
+/// Reads the upgrade request in chunks, bytes after the request
+/// are kept in the transport, so no websocket data is lost
+async fn read_request<T: AsyncRead + Unpin>(io: &mut PeekTransport<T>) -> NetResult<Request<()>> {
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        let status = req
+            .parse(io.peeked())
+            .map_err(|err| upgrade_err(UpgradeError::Parsing(err)))?;
+        if let httparse::Status::Complete(n) = status {
+            let request = build_request(&req)?;
+            io.consume(n);
+            return Ok(request);
+        }
+
+        let remaining = MAX_REQUEST_LEN - io.peeked().len();
+        if remaining == 0 {
+            return Err(NetError::WebsocketRejected(
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ));
+        }
+        if io.peek_more(remaining.min(REQUEST_CHUNK_LEN)).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
+/// Validates the upgrade request and returns the `Sec-WebSocket-Accept` value
+fn validate_request(req: &Request<()>) -> NetResult<String> {
+    if req.method() != Method::GET {
+        return Err(NetError::WebsocketRejected(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let headers = req.headers();
+    if !header(headers, "upgrade")?.eq_ignore_ascii_case("websocket") {
+        return Err(upgrade_err(UpgradeError::UpgradeNotWebSocket));
+    }
+    if !header_tokens(header(headers, "connection")?).any(|v| v.eq_ignore_ascii_case("upgrade")) {
+        return Err(upgrade_err(UpgradeError::ConnectionNotUpgrade));
+    }
+    if header(headers, "sec-websocket-version")? != "13" {
+        return Err(upgrade_err(UpgradeError::UnsupportedWebSocketVersion));
+    }
+
+    let key = header(headers, "sec-websocket-key")?;
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    ctx.update(key.as_bytes());
+    ctx.update(WS_GUID);
+    Ok(STANDARD.encode(ctx.finish()))
+}
+
+/// Selects the first of our protocols, which was offered by the client
+fn select_protocol(req: &Request<()>, protocols: &[String]) -> Option<String> {
+    let offered = req.headers().get(PROTOCOL_HEADER)?.to_str().ok()?;
+    protocols
+        .iter()
+        .find(|proto| header_tokens(offered).any(|v| v == proto.as_str()))
+        .cloned()
+}
+
+async fn reject<T: AsyncWrite + Unpin>(io: &mut T, status: StatusCode) -> NetResult<()> {
+    let allow = if status == StatusCode::METHOD_NOT_ALLOWED {
+        "Allow: GET\r\n"
+    } else {
+        ""
+    };
+    let resp = format!(
+        "HTTP/1.1 {} {}\r\n{allow}Connection: close\r\nContent-Length: 0\r\n\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    io.write_all(resp.as_bytes()).await?;
+    Ok(())
+}
+
+/// Status to reject a failed request with, `None` If reading the request failed
+/// and the connection is likely gone, so no response should be sent
+fn reject_status(err: &NetError) -> Option<StatusCode> {
+    match err {
+        NetError::WebsocketRejected(status) => Some(*status),
+        NetError::Websocket(tokio_websockets::Error::Upgrade(_)) => Some(StatusCode::BAD_REQUEST),
+        _ => None,
+    }
+}
+
+/// Performs the server side of the upgrade, the request is checked by the acceptor
+/// before the switching protocols response is sent
+pub async fn accept<T: AsyncRead + AsyncWrite + Unpin>(
+    io: &mut PeekTransport<T>,
+    protocols: &[String],
+    acceptor: Option<&WsAcceptor>,
+) -> NetResult<WsMetadata> {
+    let req = read_request(io).await;
+    let (req, ws_accept) = match req.and_then(|req| validate_request(&req).map(|a| (req, a))) {
+        Ok(req) => req,
+        Err(err) => {
+            // The rejection is best effort, the original error is returned either way
+            if let Some(status) = reject_status(&err) {
+                let _ = reject(io, status).await;
+            }
+            return Err(err);
+        }
+    };
+
+    if let Some(Err(status)) = acceptor.map(|acceptor| acceptor(&req)) {
+        let _ = reject(io, status).await;
+        return Err(NetError::WebsocketRejected(status));
+    }
+
+    let protocol = select_protocol(&req, protocols);
+    let mut resp = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {ws_accept}\r\n"
+    );
+    if let Some(protocol) = protocol.as_ref() {
+        resp.push_str(&format!("Sec-WebSocket-Protocol: {protocol}\r\n"));
+    }
+    resp.push_str("\r\n");
+    io.write_all(resp.as_bytes()).await?;
+
+    let (parts, _) = req.into_parts();
+    Ok(WsMetadata {
+        uri: parts.uri,
+        headers: parts.headers,
+        protocol,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::{codec::peek::PeekTransport, NetError};
+
+    use super::{accept, validate_request, MAX_REQUEST_LEN};
+
+    const REQUEST: &str = "GET /game HTTP/1.1\r\nHost: server\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+    /// Sends the data to the server and returns both ends
+    async fn connect(data: &[u8]) -> (DuplexStream, PeekTransport<DuplexStream>) {
+        let (mut client, server) = tokio::io::duplex(2 * MAX_REQUEST_LEN);
+        client.write_all(data).await.unwrap();
+        (client, PeekTransport::new(server))
+    }
+
+    async fn response(client: &mut DuplexStream) -> String {
+        let mut buf = vec![0; 512];
+        let n = client.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+
+    #[test]
+    fn ws_accept() {
+        // Example from RFC 6455
+        let req = http::Request::builder()
+            .header("Upgrade", "websocket")
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            validate_request(&req).unwrap(),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn leftover_bytes() -> anyhow::Result<()> {
+        // The first frame is sent together with the request
+        let (mut client, mut server) = connect(&[REQUEST.as_bytes(), b"\x82\x00"].concat()).await;
+        let meta = accept(&mut server, &[], None).await?;
+        assert_eq!(meta.uri.path(), "/game");
+        assert_eq!(server.peeked(), b"\x82\x00");
+        assert!(response(&mut client).await.starts_with("HTTP/1.1 101"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn method_rejected() {
+        let (mut client, mut server) = connect(REQUEST.replacen("GET", "POST", 1).as_bytes()).await;
+        let err = accept(&mut server, &[], None).await.unwrap_err();
+        assert!(matches!(
+            err,
+            NetError::WebsocketRejected(StatusCode::METHOD_NOT_ALLOWED)
+        ));
+        let resp = response(&mut client).await;
+        assert!(resp.starts_with("HTTP/1.1 405"));
+        assert!(resp.contains("Allow: GET"));
+    }
+
+    #[tokio::test]
+    async fn closed_not_rejected() {
+        // The peer closed the connection in the middle of the request
+        let (client, mut server) = connect(&REQUEST.as_bytes()[..32]).await;
+        drop(client);
+        let err = accept(&mut server, &[], None).await.unwrap_err();
+        assert!(matches!(
+            err,
+            NetError::IO(err) if err.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversize_rejected() {
+        let req = format!("GET / HTTP/1.1\r\nX-Pad: {}", "a".repeat(MAX_REQUEST_LEN));
+        let (mut client, mut server) = connect(req.as_bytes()).await;
+        let err = accept(&mut server, &[], None).await.unwrap_err();
+        assert!(matches!(
+            err,
+            NetError::WebsocketRejected(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        ));
+        assert!(response(&mut client).await.starts_with("HTTP/1.1 431"));
+    }
+}
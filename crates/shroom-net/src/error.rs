@@ -3,12 +3,20 @@ use std::{io, str::Utf8Error};
 use shroom_pkt::Error;
 use thiserror::Error;
 
+use crate::{codec::legacy::handshake::Handshake, ratelimit::RateLimitKind, timeout::TimeoutKind};
+
 #[derive(Debug, Error)]
 pub enum NetError {
     #[error("IO")]
     IO(#[from] io::Error),
     #[error("Websocket")]
     Websocket(#[from] tokio_websockets::Error),
+    #[error("Unexpected websocket text frame")]
+    WebsocketText,
+    #[error("Websocket frame of length {0} does not contain exactly one packet")]
+    WebsocketFrame(usize),
+    #[error("Websocket upgrade rejected with {0}")]
+    WebsocketRejected(http::StatusCode),
     #[error("Packet")]
     Packet(#[from] Error),
     #[error("string utf8 error")]
@@ -27,10 +35,32 @@ pub enum NetError {
     HandshakeSize(usize),
     #[error("Unable to read handshake")]
     InvalidHandshake,
+    #[error("Handshake rejected: {0:?}")]
+    HandshakeRejected(Box<Handshake>),
+    #[error("Handshake not completed")]
+    HandshakePending,
     #[error("Invalid AES key")]
     InvalidAESKey,
     #[error("Invalid timestamp: {0}")]
     InvalidTimestamp(i64),
     #[error("Invalid opcode: {0:X}")]
     InvalidOpCode(u16),
+    #[error("{0} timeout expired")]
+    Timeout(TimeoutKind),
+    #[error("{0} rate limit exceeded")]
+    RateLimited(RateLimitKind),
+    #[error("Outgoing queue is full")]
+    QueueFull,
+    #[error("Session closed")]
+    SessionClosed,
+    #[error("Session panicked: {0}")]
+    SessionPanicked(String),
+    #[error("Invalid PROXY protocol header: {0}")]
+    ProxyProtocol(&'static str),
+    #[cfg(feature = "tls")]
+    #[error("TLS")]
+    Tls(#[from] tokio_rustls::rustls::Error),
+    #[cfg(feature = "tls")]
+    #[error("PEM")]
+    Pem(#[from] rustls_pki_types::pem::Error),
 }
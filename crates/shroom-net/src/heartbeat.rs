@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use shroom_pkt::Packet;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use crate::{timeout::TimeoutKind, NetError, NetResult};
+
+/// Heartbeat driver, which sends a ping in an interval and
+/// expects a pong to be received before the deadline
+pub struct Heartbeat {
+    interval: Interval,
+    timeout: Duration,
+    ping: Packet,
+    is_pong: Box<dyn Fn(&Packet) -> bool + Send + Sync>,
+    pending: Option<Instant>,
+    latency: Option<Duration>,
+}
+
+impl std::fmt::Debug for Heartbeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Heartbeat")
+            .field("period", &self.interval.period())
+            .field("timeout", &self.timeout)
+            .field("pending", &self.pending)
+            .field("latency", &self.latency)
+            .finish()
+    }
+}
+
+impl Heartbeat {
+    /// Creates a heartbeat, which sends the `ping` every `interval`,
+    /// `is_pong` is used to check whether a received packet is the pong
+    pub fn new(
+        interval: Duration,
+        timeout: Duration,
+        ping: Packet,
+        is_pong: impl Fn(&Packet) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        let mut interval = tokio::time::interval_at(Instant::now() + interval, interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            interval,
+            timeout,
+            ping,
+            is_pong: Box::new(is_pong),
+            pending: None,
+            latency: None,
+        }
+    }
+
+    /// Waits until the next ping has to be sent and returns It,
+    /// fails If the pending ping was not answered before the deadline
+    ///
+    /// This method is cancel safe
+    pub async fn tick(&mut self) -> NetResult<Packet> {
+        if let Some(sent) = self.pending {
+            tokio::time::sleep_until(sent + self.timeout).await;
+            return Err(NetError::Timeout(TimeoutKind::Heartbeat));
+        }
+
+        self.interval.tick().await;
+        self.pending = Some(Instant::now());
+        Ok(self.ping.clone())
+    }
+
+    /// Handles a received packet, returns true If the packet was the pong
+    pub fn on_packet(&mut self, pkt: &Packet) -> bool {
+        if !(self.is_pong)(pkt) {
+            return false;
+        }
+
+        if let Some(sent) = self.pending.take() {
+            self.latency = Some(sent.elapsed());
+        }
+        true
+    }
+
+    /// Round-trip time of the last answered ping
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Whether a ping is waiting for Its pong
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::SharedCryptoContext;
+    use shroom_pkt::Packet;
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::{
+        codec::{
+            legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda},
+            ShroomCodec,
+        },
+        timeout::{SessionTimeouts, TimeoutKind},
+        NetError,
+    };
+
+    use super::Heartbeat;
+
+    const PORT: u16 = 1738;
+    const PING: &[u8] = &[0xAA];
+    const PONG: &[u8] = &[0xBB];
+
+    fn codec() -> LegacyCodecNoShanda<turmoil::net::TcpStream> {
+        LegacyCodecNoShanda::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    fn heartbeat() -> Heartbeat {
+        Heartbeat::new(
+            Duration::from_secs(1),
+            Duration::from_millis(500),
+            Packet::from_static(PING),
+            |pkt| pkt[..] == *PONG,
+        )
+    }
+
+    fn sim(answer_pings: bool) -> turmoil::Sim<'static> {
+        let mut sim = turmoil::Builder::new().build();
+        sim.host("server", move || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            loop {
+                let socket = listener.accept().await?.0;
+                let mut sess = codec().create_server(socket).await?;
+                while let Some(Ok(pkt)) = sess.next().await {
+                    if answer_pings && pkt[..] == *PING {
+                        sess.send(PONG).await?;
+                    }
+                }
+            }
+        });
+        sim
+    }
+
+    #[test]
+    fn heartbeat_latency() -> anyhow::Result<()> {
+        let mut sim = sim(true);
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            let mut hb = heartbeat();
+            // Pongs are consumed, so no packet must be returned
+            let res =
+                tokio::time::timeout(Duration::from_secs(3), sess.next_with_heartbeat(&mut hb))
+                    .await;
+            assert!(res.is_err());
+            assert!(hb.latency().is_some());
+            Ok(())
+        });
+        sim.run().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn heartbeat_timeout() -> anyhow::Result<()> {
+        let mut sim = sim(false);
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            let mut hb = heartbeat();
+            let res = sess.next_with_heartbeat(&mut hb).await;
+            assert!(matches!(
+                res,
+                Some(Err(NetError::Timeout(TimeoutKind::Heartbeat)))
+            ));
+            assert!(hb.latency().is_none());
+            Ok(())
+        });
+        sim.run().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn read_idle_timeout() -> anyhow::Result<()> {
+        let mut sim = sim(false);
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let timeouts = SessionTimeouts::default().with_read_idle(Duration::from_secs(1));
+            let mut sess = codec()
+                .create_client_with_timeouts(socket, timeouts)
+                .await?;
+            assert!(matches!(
+                sess.next().await,
+                Some(Err(NetError::Timeout(TimeoutKind::ReadIdle)))
+            ));
+            Ok(())
+        });
+        sim.run().unwrap();
+        Ok(())
+    }
+}
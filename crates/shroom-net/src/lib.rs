@@ -1,10 +1,21 @@
+pub mod broadcast;
 pub mod codec;
 pub mod error;
+pub mod heartbeat;
+pub mod metrics;
+pub mod proxy;
+pub mod ratelimit;
+pub mod record;
+pub mod server;
 pub mod stream;
+pub mod timeout;
+#[cfg(feature = "tracing")]
+pub mod trace;
 
 pub use error::NetError;
 pub use shroom_crypto::{CryptoContext, SharedCryptoContext};
 pub use shroom_pkt::Packet;
+pub use server::ShroomServer;
 pub use stream::ShroomStream;
 
 pub type NetResult<T> = Result<T, error::NetError>;
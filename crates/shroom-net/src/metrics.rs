@@ -0,0 +1,480 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::{buf::UninitSlice, BufMut};
+use shroom_pkt::pkt::EncodeMessage;
+use tokio::time::Instant;
+
+use crate::NetError;
+
+/// Hooks, which are called by the sessions on each event,
+/// all hooks do nothing by default. The handshake is only covered, If the
+/// session is created with `create_client_with_metrics` or `create_server_with_metrics`,
+/// sessions with metrics attached by `with_metrics` only report the events afterwards
+pub trait NetMetrics: Send + Sync + 'static {
+    /// Session was opened after the handshake
+    fn session_opened(&self) {}
+
+    /// Session was closed, the stats contain the totals of the session
+    fn session_closed(&self, _stats: &SessionStats) {}
+
+    /// Handshake was completed in the duration
+    fn handshake_completed(&self, _duration: Duration) {}
+
+    /// Handshake failed or timed out, invalid headers and rejected
+    /// frames are reported by their own hooks aswell
+    fn handshake_failed(&self, _err: &NetError) {}
+
+    /// Packet with the payload length was received
+    fn packet_received(&self, _len: usize) {}
+
+    /// Packet with the payload length was sent
+    fn packet_sent(&self, _len: usize) {}
+
+    /// Packet header failed the validation
+    fn invalid_header(&self) {}
+
+    /// Frame was rejected, because It exceeds the max length
+    fn frame_size_rejected(&self, _len: usize) {}
+}
+
+pub type SharedNetMetrics = Arc<dyn NetMetrics>;
+
+/// Statistics of a single session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionStats {
+    /// Received packets
+    pub packets_in: u64,
+    /// Sent packets
+    pub packets_out: u64,
+    /// Payload bytes of the received packets
+    pub bytes_in: u64,
+    /// Payload bytes of the sent packets
+    pub bytes_out: u64,
+    /// Packet headers, which failed the validation
+    pub invalid_headers: u64,
+    /// Frames, which were rejected because of their length
+    pub frame_size_rejections: u64,
+    /// Duration of the handshake, only set If the session was created
+    /// with the `*_with_timeouts` or `*_with_metrics` methods of the codec
+    pub handshake_duration: Option<Duration>,
+    /// Time the session was created
+    pub opened_at: Instant,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self {
+            packets_in: 0,
+            packets_out: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            invalid_headers: 0,
+            frame_size_rejections: 0,
+            handshake_duration: None,
+            opened_at: Instant::now(),
+        }
+    }
+}
+
+impl SessionStats {
+    /// Gets the time since the session was created
+    pub fn lifetime(&self) -> Duration {
+        self.opened_at.elapsed()
+    }
+}
+
+/// Reports the failed handshake to the metrics
+pub(crate) fn handshake_failed(metrics: &dyn NetMetrics, err: &NetError) {
+    metrics.handshake_failed(err);
+    match err {
+        NetError::InvalidHeader(_) => metrics.invalid_header(),
+        NetError::FrameSize(len) => metrics.frame_size_rejected(*len),
+        _ => {}
+    }
+}
+
+/// Records the stats of a session and reports them to the metrics,
+/// the closed session is reported when the recorder is dropped
+#[derive(Default)]
+pub(crate) struct StatsRecorder {
+    stats: SessionStats,
+    metrics: Option<SharedNetMetrics>,
+}
+
+impl StatsRecorder {
+    pub(crate) fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    pub(crate) fn set_metrics(&mut self, metrics: SharedNetMetrics) {
+        metrics.session_opened();
+        if let Some(duration) = self.stats.handshake_duration {
+            metrics.handshake_completed(duration);
+        }
+        if let Some(prev) = self.metrics.replace(metrics) {
+            prev.session_closed(&self.stats);
+        }
+    }
+
+    pub(crate) fn handshake(&mut self, duration: Duration) {
+        self.stats.handshake_duration = Some(duration);
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.handshake_completed(duration);
+        }
+    }
+
+    pub(crate) fn received(&mut self, len: usize) {
+        self.stats.packets_in += 1;
+        self.stats.bytes_in += len as u64;
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.packet_received(len);
+        }
+    }
+
+    pub(crate) fn sent(&mut self, len: usize) {
+        self.stats.packets_out += 1;
+        self.stats.bytes_out += len as u64;
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.packet_sent(len);
+        }
+    }
+
+    /// Records the error If It's relevant for the stats
+    pub(crate) fn error(&mut self, err: &NetError) {
+        match err {
+            NetError::InvalidHeader(_) => {
+                self.stats.invalid_headers += 1;
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.invalid_header();
+                }
+            }
+            NetError::FrameSize(len) => {
+                self.stats.frame_size_rejections += 1;
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.frame_size_rejected(*len);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for StatsRecorder {
+    fn drop(&mut self) {
+        if let Some(metrics) = self.metrics.take() {
+            metrics.session_closed(&self.stats);
+        }
+    }
+}
+
+/// Buffer, which counts the bytes written into the inner buffer
+struct CountingBuf<'a, B> {
+    inner: B,
+    len: &'a mut usize,
+}
+
+// SAFETY all calls are forwarded to the inner buffer
+unsafe impl<B: BufMut> BufMut for CountingBuf<'_, B> {
+    fn remaining_mut(&self) -> usize {
+        self.inner.remaining_mut()
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        *self.len += cnt;
+        unsafe { self.inner.advance_mut(cnt) }
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        self.inner.chunk_mut()
+    }
+}
+
+/// Message, which counts Its encoded length
+pub(crate) struct CountingMsg<'a, T> {
+    pub(crate) msg: T,
+    pub(crate) len: &'a mut usize,
+}
+
+impl<T: EncodeMessage> EncodeMessage for CountingMsg<'_, T> {
+    fn encode_message<B: BufMut>(self, buf: B) -> Result<(), shroom_pkt::Error> {
+        self.msg.encode_message(CountingBuf {
+            inner: buf,
+            len: self.len,
+        })
+    }
+}
+
+/// In-process aggregator of the metrics of all sessions, which can be
+/// shared with an exporter for any metrics backend
+#[derive(Debug, Default)]
+pub struct MetricsAggregator {
+    sessions_opened: AtomicU64,
+    sessions_closed: AtomicU64,
+    session_lifetime_us: AtomicU64,
+    handshakes: AtomicU64,
+    handshake_us: AtomicU64,
+    handshake_failures: AtomicU64,
+    packets_in: AtomicU64,
+    packets_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    invalid_headers: AtomicU64,
+    frame_size_rejections: AtomicU64,
+}
+
+/// Snapshot of the aggregated metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AggregateStats {
+    /// Opened sessions
+    pub sessions_opened: u64,
+    /// Closed sessions
+    pub sessions_closed: u64,
+    /// Summed lifetime of the closed sessions
+    pub session_lifetime: Duration,
+    /// Completed handshakes
+    pub handshakes: u64,
+    /// Summed duration of the completed handshakes
+    pub handshake_duration: Duration,
+    /// Failed or timed out handshakes
+    pub handshake_failures: u64,
+    /// Received packets
+    pub packets_in: u64,
+    /// Sent packets
+    pub packets_out: u64,
+    /// Payload bytes of the received packets
+    pub bytes_in: u64,
+    /// Payload bytes of the sent packets
+    pub bytes_out: u64,
+    /// Packet headers, which failed the validation
+    pub invalid_headers: u64,
+    /// Frames, which were rejected because of their length
+    pub frame_size_rejections: u64,
+}
+
+impl AggregateStats {
+    /// Gets the number of sessions, which are still open
+    pub fn active_sessions(&self) -> u64 {
+        self.sessions_opened.saturating_sub(self.sessions_closed)
+    }
+
+    /// Gets the average handshake duration
+    pub fn avg_handshake_duration(&self) -> Option<Duration> {
+        avg(self.handshake_duration, self.handshakes)
+    }
+
+    /// Gets the average lifetime of the closed sessions
+    pub fn avg_session_lifetime(&self) -> Option<Duration> {
+        avg(self.session_lifetime, self.sessions_closed)
+    }
+}
+
+fn avg(total: Duration, n: u64) -> Option<Duration> {
+    (n > 0).then(|| Duration::from_micros((total.as_micros() / n as u128) as u64))
+}
+
+impl MetricsAggregator {
+    /// Creates a new shared aggregator
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Takes a snapshot of the metrics
+    pub fn stats(&self) -> AggregateStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        AggregateStats {
+            sessions_opened: load(&self.sessions_opened),
+            sessions_closed: load(&self.sessions_closed),
+            session_lifetime: Duration::from_micros(load(&self.session_lifetime_us)),
+            handshakes: load(&self.handshakes),
+            handshake_duration: Duration::from_micros(load(&self.handshake_us)),
+            handshake_failures: load(&self.handshake_failures),
+            packets_in: load(&self.packets_in),
+            packets_out: load(&self.packets_out),
+            bytes_in: load(&self.bytes_in),
+            bytes_out: load(&self.bytes_out),
+            invalid_headers: load(&self.invalid_headers),
+            frame_size_rejections: load(&self.frame_size_rejections),
+        }
+    }
+
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+impl NetMetrics for MetricsAggregator {
+    fn session_opened(&self) {
+        Self::add(&self.sessions_opened, 1);
+    }
+
+    fn session_closed(&self, stats: &SessionStats) {
+        Self::add(&self.sessions_closed, 1);
+        Self::add(
+            &self.session_lifetime_us,
+            stats.lifetime().as_micros() as u64,
+        );
+    }
+
+    fn handshake_completed(&self, duration: Duration) {
+        Self::add(&self.handshakes, 1);
+        Self::add(&self.handshake_us, duration.as_micros() as u64);
+    }
+
+    fn handshake_failed(&self, _err: &NetError) {
+        Self::add(&self.handshake_failures, 1);
+    }
+
+    fn packet_received(&self, len: usize) {
+        Self::add(&self.packets_in, 1);
+        Self::add(&self.bytes_in, len as u64);
+    }
+
+    fn packet_sent(&self, len: usize) {
+        Self::add(&self.packets_out, 1);
+        Self::add(&self.bytes_out, len as u64);
+    }
+
+    fn invalid_header(&self) {
+        Self::add(&self.invalid_headers, 1);
+    }
+
+    fn frame_size_rejected(&self, _len: usize) {
+        Self::add(&self.frame_size_rejections, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::SharedCryptoContext;
+    use shroom_pkt::Packet;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{
+        codec::{
+            legacy::{
+                handshake::Handshake, handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda,
+                MAX_PACKET_LEN,
+            },
+            memory::{MemoryListener, MemoryTransport},
+            ShroomCodec,
+        },
+        server::SessionShutdown,
+        timeout::SessionTimeouts,
+        NetError, NetResult, ShroomServer, ShroomStream,
+    };
+
+    use super::{MetricsAggregator, SharedNetMetrics};
+
+    type Codec = LegacyCodecNoShanda<MemoryTransport>;
+
+    fn codec() -> Codec {
+        LegacyCodecNoShanda::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    async fn echo(mut sess: ShroomStream<Codec>, _shutdown: SessionShutdown) -> NetResult<()> {
+        while let Some(Ok(pkt)) = sess.next().await {
+            sess.send(pkt).await?;
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Ping(u32);
+    shroom_pkt::packet_wrap!(Ping<>, u32, u32);
+
+    impl From<u32> for Ping {
+        fn from(v: u32) -> Self {
+            Self(v)
+        }
+    }
+
+    impl From<Ping> for u32 {
+        fn from(v: Ping) -> Self {
+            v.0
+        }
+    }
+
+    impl shroom_pkt::HasOpCode for Ping {
+        type OpCode = u16;
+
+        const OPCODE: u16 = 0x11;
+    }
+
+    #[tokio::test]
+    async fn session_metrics() -> anyhow::Result<()> {
+        let metrics = MetricsAggregator::new();
+        let listener = MemoryListener::bind("10.0.0.1:8484".parse()?);
+        let connector = listener.connector();
+        let server = ShroomServer::new(codec(), listener).with_metrics(metrics.clone());
+        let handle = server.shutdown_handle();
+        let server = tokio::spawn(server.run(echo));
+
+        let client_metrics = MetricsAggregator::new();
+        let mut sess = codec()
+            .create_client_with_timeouts(connector.connect()?, SessionTimeouts::default())
+            .await?
+            .with_metrics(Some(client_metrics.clone() as SharedNetMetrics));
+        assert!(sess.stats().handshake_duration.is_some());
+
+        sess.send(Packet::from_static(&[1, 0, 2])).await?;
+        sess.next().await.unwrap()?;
+        // Messages are counted with the opcode
+        sess.send_msg(Ping(7)).await?;
+        sess.next().await.unwrap()?;
+        assert!(matches!(
+            sess.send(vec![0; MAX_PACKET_LEN + 1]).await,
+            Err(NetError::FrameSize(_))
+        ));
+
+        let stats = *sess.stats();
+        assert_eq!(
+            (
+                stats.packets_out,
+                stats.bytes_out,
+                stats.packets_in,
+                stats.bytes_in
+            ),
+            (2, 9, 2, 9)
+        );
+        assert_eq!(stats.frame_size_rejections, 1);
+        assert_eq!(client_metrics.stats().active_sessions(), 1);
+        drop(sess);
+
+        let client = client_metrics.stats();
+        assert_eq!((client.sessions_opened, client.sessions_closed), (1, 1));
+        assert_eq!((client.packets_out, client.bytes_out), (2, 9));
+        assert_eq!((client.handshakes, client.frame_size_rejections), (1, 1));
+
+        // Closed before the server sent the handshake
+        drop(connector.connect()?);
+
+        // Send an invalid header after the handshake
+        let mut raw = connector.connect()?;
+        Handshake::read_handshake_async(&mut raw).await?;
+        raw.write_all(&[0xFF; 8]).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        handle.shutdown();
+        server.await??;
+        let server = metrics.stats();
+        assert_eq!((server.sessions_opened, server.sessions_closed), (2, 2));
+        assert_eq!((server.handshakes, server.handshake_failures), (2, 1));
+        assert_eq!((server.packets_in, server.bytes_in), (2, 9));
+        assert_eq!((server.packets_out, server.bytes_out), (2, 9));
+        assert_eq!(server.invalid_headers, 1);
+        assert!(server.avg_session_lifetime().is_some());
+        Ok(())
+    }
+}
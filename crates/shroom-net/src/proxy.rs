@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+
+use futures::{SinkExt, StreamExt};
+use shroom_crypto::RoundKey;
+use shroom_pkt::Packet;
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    task::{JoinError, JoinSet},
+};
+
+use crate::{
+    codec::{
+        legacy::{handshake::Handshake, LegacyCodec},
+        ShroomTransport,
+    },
+    server::is_transient_accept_err,
+    NetError, NetResult,
+};
+
+/// Direction of a proxied packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyDirection {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// Action for a proxied packet
+#[derive(Debug, Clone)]
+pub enum ProxyAction {
+    /// Forward the packet unchanged
+    Forward,
+    /// Drop the packet
+    Drop,
+    /// Forward the given packet instead
+    Replace(Packet),
+}
+
+/// Handler, which is called for every packet passing the proxy
+pub trait ProxyHandler: Send {
+    fn on_packet(&mut self, dir: ProxyDirection, pkt: &Packet) -> ProxyAction;
+
+    /// Called by `ShroomProxy::serve` If the session failed, this includes
+    /// failed upstream connects and handshakes
+    fn on_session_error(&mut self, _err: &NetError) {}
+}
+
+/// Forwards every packet unchanged
+impl ProxyHandler for () {
+    fn on_packet(&mut self, _dir: ProxyDirection, _pkt: &Packet) -> ProxyAction {
+        ProxyAction::Forward
+    }
+}
+
+type Hook = Box<dyn FnMut(&Packet) -> ProxyAction + Send>;
+type Observer = Box<dyn FnMut(ProxyDirection, &Packet) + Send>;
+type ErrorObserver = Box<dyn FnMut(&NetError) + Send>;
+
+/// Proxy handler, which allows to register hooks per opcode
+#[derive(Default)]
+pub struct OpcodeHooks {
+    hooks: HashMap<(ProxyDirection, u16), Hook>,
+    observer: Option<Observer>,
+    error_observer: Option<ErrorObserver>,
+}
+
+impl std::fmt::Debug for OpcodeHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpcodeHooks")
+            .field("hooks", &self.hooks.keys())
+            .finish()
+    }
+}
+
+impl OpcodeHooks {
+    /// Registers a hook for the opcode in the given direction
+    pub fn on(
+        mut self,
+        dir: ProxyDirection,
+        opcode: u16,
+        hook: impl FnMut(&Packet) -> ProxyAction + Send + 'static,
+    ) -> Self {
+        self.hooks.insert((dir, opcode), Box::new(hook));
+        self
+    }
+
+    /// Drops all packets with the opcode in the given direction
+    pub fn drop(self, dir: ProxyDirection, opcode: u16) -> Self {
+        self.on(dir, opcode, |_| ProxyAction::Drop)
+    }
+
+    /// Rewrites all packets with the opcode in the given direction
+    pub fn rewrite(
+        self,
+        dir: ProxyDirection,
+        opcode: u16,
+        mut rewrite: impl FnMut(&Packet) -> Packet + Send + 'static,
+    ) -> Self {
+        self.on(dir, opcode, move |pkt| ProxyAction::Replace(rewrite(pkt)))
+    }
+
+    /// Sets an observer, which sees every packet before the hooks, useful for logging
+    pub fn observe(
+        mut self,
+        observer: impl FnMut(ProxyDirection, &Packet) + Send + 'static,
+    ) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Sets an observer, which sees the error of the session If It failed
+    pub fn observe_errors(mut self, observer: impl FnMut(&NetError) + Send + 'static) -> Self {
+        self.error_observer = Some(Box::new(observer));
+        self
+    }
+}
+
+impl ProxyHandler for OpcodeHooks {
+    fn on_packet(&mut self, dir: ProxyDirection, pkt: &Packet) -> ProxyAction {
+        if let Some(observer) = self.observer.as_mut() {
+            observer(dir, pkt);
+        }
+
+        let Some(op) = pkt.get(..2) else {
+            return ProxyAction::Forward;
+        };
+        let op = u16::from_le_bytes(op.try_into().expect("opcode"));
+        match self.hooks.get_mut(&(dir, op)) {
+            Some(hook) => hook(pkt),
+            None => ProxyAction::Forward,
+        }
+    }
+
+    fn on_session_error(&mut self, err: &NetError) {
+        if let Some(observer) = self.error_observer.as_mut() {
+            observer(err);
+        }
+    }
+}
+
+/// Man-in-the-middle proxy, which connects to the upstream server and
+/// passes Its version, subversion and locale on to the client with fresh IVs
+pub struct ShroomProxy<const C: u8, T = TcpStream> {
+    codec: LegacyCodec<C, T>,
+}
+
+impl<const C: u8, T: ShroomTransport + Sync> ShroomProxy<C, T> {
+    /// Creates a proxy, which uses the codec for both sides
+    pub fn new(codec: LegacyCodec<C, T>) -> Self {
+        Self { codec }
+    }
+
+    /// Creates the handshake for the client from the upstream handshake
+    fn client_handshake(upstream: &Handshake) -> Handshake {
+        let mut rng = rand::thread_rng();
+        Handshake {
+            iv_enc: RoundKey::get_random(&mut rng),
+            iv_dec: RoundKey::get_random(&mut rng),
+            ..upstream.clone()
+        }
+    }
+
+    /// Proxies a session between the client and the upstream transport,
+    /// until one of both sides closes the connection
+    pub async fn run_session<H: ProxyHandler>(
+        &self,
+        client: T,
+        upstream: T,
+        handler: &mut H,
+    ) -> NetResult<()> {
+        let (mut server, hshake) = self.codec.create_client_with_handshake(upstream).await?;
+        let mut client = self
+            .codec
+            .create_server_with_handshake(client, &Self::client_handshake(&hshake))
+            .await?;
+
+        loop {
+            let (dir, pkt) = tokio::select! {
+                pkt = client.next() => (ProxyDirection::ClientToServer, pkt),
+                pkt = server.next() => (ProxyDirection::ServerToClient, pkt),
+            };
+            let Some(pkt) = pkt.transpose()? else {
+                break;
+            };
+
+            let pkt = match handler.on_packet(dir, &pkt) {
+                ProxyAction::Forward => pkt,
+                ProxyAction::Drop => continue,
+                ProxyAction::Replace(pkt) => pkt,
+            };
+            match dir {
+                ProxyDirection::ClientToServer => server.send(pkt).await?,
+                ProxyDirection::ServerToClient => client.send(pkt).await?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<const C: u8> ShroomProxy<C, TcpStream> {
+    /// Accepts clients on the listener and proxies each one to the upstream address
+    /// in a separate task, `make_handler` creates the handler for each session.
+    /// Errors of the sessions are passed to `ProxyHandler::on_session_error`,
+    /// on a fatal accept error the running sessions are awaited before returning
+    pub async fn serve<A, H>(
+        self,
+        listener: TcpListener,
+        upstream: A,
+        make_handler: impl Fn() -> H,
+    ) -> NetResult<()>
+    where
+        A: ToSocketAddrs + Clone + Send + Sync + 'static,
+        H: ProxyHandler + 'static,
+    {
+        let proxy = std::sync::Arc::new(self);
+        let mut sessions = JoinSet::new();
+        // Errors of the sessions were already reported
+        // and sessions are never aborted, so only panics are left
+        let reap = |res: Result<(), JoinError>| {
+            if let Err(err) = res {
+                std::panic::resume_unwind(err.into_panic());
+            }
+        };
+
+        let err = loop {
+            tokio::select! {
+                // Reap finished sessions
+                Some(res) = sessions.join_next(), if !sessions.is_empty() => reap(res),
+                res = listener.accept() => {
+                    let client = match res {
+                        Ok((client, _)) => client,
+                        Err(err) if is_transient_accept_err(&err) => continue,
+                        Err(err) => break err,
+                    };
+                    let (proxy, upstream) = (proxy.clone(), upstream.clone());
+                    let mut handler = make_handler();
+                    sessions.spawn(async move {
+                        let res = match TcpStream::connect(upstream).await {
+                            Ok(upstream) => proxy.run_session(client, upstream, &mut handler).await,
+                            Err(err) => Err(err.into()),
+                        };
+                        if let Err(err) = res {
+                            handler.on_session_error(&err);
+                        }
+                    });
+                }
+            }
+        };
+
+        while let Some(res) = sessions.join_next().await {
+            reap(res);
+        }
+        Err(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::ErrorKind,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::SharedCryptoContext;
+    use shroom_pkt::Packet;
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::codec::{
+        legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda},
+        ShroomCodec,
+    };
+
+    use crate::NetError;
+
+    use super::{OpcodeHooks, ProxyDirection, ShroomProxy};
+
+    const PORT: u16 = 1738;
+
+    fn codec(hshake: BasicHandshakeGenerator) -> LegacyCodecNoShanda<turmoil::net::TcpStream> {
+        LegacyCodecNoShanda::new(SharedCryptoContext::default(), hshake)
+    }
+
+    #[test]
+    fn proxy() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            loop {
+                let socket = listener.accept().await?.0;
+                let mut sess = codec(BasicHandshakeGenerator::v95())
+                    .create_server(socket)
+                    .await?;
+                while let Some(Ok(pkt)) = sess.next().await {
+                    sess.send(pkt).await?;
+                }
+            }
+        });
+
+        sim.host("proxy", || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            // Generator is not used by the proxy
+            let proxy = ShroomProxy::new(codec(BasicHandshakeGenerator::v83()));
+            loop {
+                let client = listener.accept().await?.0;
+                let upstream = TcpStream::connect(("server", PORT)).await?;
+                let mut hooks = OpcodeHooks::default()
+                    .drop(ProxyDirection::ClientToServer, 2)
+                    .rewrite(ProxyDirection::ServerToClient, 3, |_| {
+                        Packet::from_static(&[3, 0, 9])
+                    });
+                proxy.run_session(client, upstream, &mut hooks).await?;
+            }
+        });
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("proxy", PORT)).await?;
+            let (mut sess, hshake) = codec(BasicHandshakeGenerator::v83())
+                .create_client_with_handshake(socket)
+                .await?;
+            assert_eq!(hshake.version, 95.into());
+
+            sess.send(Packet::from_static(&[1, 0, 1])).await?;
+            assert_eq!(&sess.next().await.unwrap()?[..], &[1, 0, 1]);
+
+            // First packet is dropped, the echo of the second is rewritten
+            sess.send(Packet::from_static(&[2, 0, 1])).await?;
+            sess.send(Packet::from_static(&[3, 0, 5])).await?;
+            assert_eq!(&sess.next().await.unwrap()?[..], &[3, 0, 9]);
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_errors() -> anyhow::Result<()> {
+        // Upstream, which refuses the connection
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let upstream_addr = upstream.local_addr()?;
+        drop(upstream);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (tx, mut errors) = tokio::sync::mpsc::unbounded_channel();
+        let proxy = ShroomProxy::new(LegacyCodecNoShanda::<tokio::net::TcpStream>::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        ));
+        let server = tokio::spawn(proxy.serve(listener, upstream_addr, move || {
+            let tx = tx.clone();
+            OpcodeHooks::default().observe_errors(move |err| {
+                let _ = tx.send(matches!(
+                    err,
+                    NetError::IO(err) if err.kind() == ErrorKind::ConnectionRefused
+                ));
+            })
+        }));
+
+        let _client = tokio::net::TcpStream::connect(addr).await?;
+        assert_eq!(errors.recv().await, Some(true));
+        server.abort();
+        Ok(())
+    }
+}
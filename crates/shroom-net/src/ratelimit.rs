@@ -0,0 +1,437 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Action, which is taken when a limit is exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitAction {
+    /// Delays the packet until enough tokens are available,
+    /// no further packets are read in the meantime
+    #[default]
+    Delay,
+    /// Drops the packet
+    Drop,
+    /// Fails the stream with `NetError::RateLimited`
+    Close,
+}
+
+/// Kind of the exceeded limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitKind {
+    /// Packets per second
+    Packets,
+    /// Bytes per second
+    Bytes,
+    /// Packets per second of the opcode
+    OpCode(u16),
+}
+
+impl std::fmt::Display for RateLimitKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Packets => write!(f, "packets"),
+            Self::Bytes => write!(f, "bytes"),
+            Self::OpCode(op) => write!(f, "opcode {op:X}"),
+        }
+    }
+}
+
+/// Limit with a sustained rate per second and a burst size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub rate: u32,
+    pub burst: u32,
+    pub action: RateLimitAction,
+}
+
+impl Limit {
+    /// Creates a limit, which delays packets, will panic if the rate is zero
+    pub fn new(rate: u32, burst: u32) -> Self {
+        assert!(rate > 0, "Rate must not be zero");
+        Self {
+            rate,
+            burst,
+            action: RateLimitAction::default(),
+        }
+    }
+
+    /// Sets the action
+    pub fn with_action(mut self, action: RateLimitAction) -> Self {
+        self.action = action;
+        self
+    }
+}
+
+/// Token bucket, which is refilled with the rate of the limit
+#[derive(Debug)]
+struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let dt = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + dt * self.limit.rate as f64).min(self.limit.burst as f64);
+        self.last = now;
+    }
+
+    /// Time until `n` tokens are available, `None` if they are available now
+    fn wait_time(&self, n: f64) -> Option<Duration> {
+        (self.tokens < n)
+            .then(|| Duration::from_secs_f64((n - self.tokens) / self.limit.rate as f64))
+    }
+
+    /// Takes `n` tokens, the bucket goes into debt If there're not enough
+    fn take(&mut self, n: f64) {
+        self.tokens -= n;
+    }
+}
+
+/// Hook, which returns the limit for an opcode
+pub type OpCodeLimitHook = Arc<dyn Fn(u16) -> Option<Limit> + Send + Sync>;
+
+/// Limits for incoming packets, `None` disables a limit
+#[derive(Clone, Default)]
+pub struct RateLimitConfig {
+    /// Packets per second
+    pub packets: Option<Limit>,
+    /// Bytes per second
+    pub bytes: Option<Limit>,
+    opcode_hook: Option<OpCodeLimitHook>,
+}
+
+impl std::fmt::Debug for RateLimitConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimitConfig")
+            .field("packets", &self.packets)
+            .field("bytes", &self.bytes)
+            .field("opcode_hook", &self.opcode_hook.is_some())
+            .finish()
+    }
+}
+
+impl RateLimitConfig {
+    /// Sets the packets per second limit
+    pub fn with_packets(mut self, limit: Limit) -> Self {
+        self.packets = Some(limit);
+        self
+    }
+
+    /// Sets the bytes per second limit
+    pub fn with_bytes(mut self, limit: Limit) -> Self {
+        self.bytes = Some(limit);
+        self
+    }
+
+    /// Sets the hook, which returns the packets per second limit of an opcode.
+    /// Only returned limits are cached, so the hook is called again
+    /// for every packet of an opcode without a limit and should be cheap
+    pub fn with_opcode_limits(
+        mut self,
+        hook: impl Fn(u16) -> Option<Limit> + Send + Sync + 'static,
+    ) -> Self {
+        self.opcode_hook = Some(Arc::new(hook));
+        self
+    }
+}
+
+/// Counters of a rate limiter, which can be shared with other tasks
+#[derive(Debug, Default)]
+pub struct RateLimitCounters {
+    passed: AtomicU64,
+    delayed: AtomicU64,
+    dropped: AtomicU64,
+    closed: AtomicU64,
+}
+
+/// Snapshot of the counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimitStats {
+    /// Packets, which passed without delay
+    pub passed: u64,
+    /// Packets, which were delayed
+    pub delayed: u64,
+    /// Packets, which were dropped
+    pub dropped: u64,
+    /// Packets, which closed the stream
+    pub closed: u64,
+}
+
+impl RateLimitCounters {
+    /// Takes a snapshot of the counters
+    pub fn stats(&self) -> RateLimitStats {
+        RateLimitStats {
+            passed: self.passed.load(Ordering::Relaxed),
+            delayed: self.delayed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            closed: self.closed.load(Ordering::Relaxed),
+        }
+    }
+
+    fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Decision of the rate limiter for a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    Pass,
+    Delay(Duration),
+    Drop,
+    Close(RateLimitKind),
+}
+
+/// Rate limiter for the packets of a single session
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    /// Buckets of the opcodes with a limit
+    opcodes: HashMap<u16, TokenBucket>,
+    counters: Arc<RateLimitCounters>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter with full buckets
+    pub fn new(config: RateLimitConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            packets: config.packets.map(|limit| TokenBucket::new(limit, now)),
+            bytes: config.bytes.map(|limit| TokenBucket::new(limit, now)),
+            opcodes: HashMap::new(),
+            config,
+            counters: Arc::default(),
+        }
+    }
+
+    /// Gets the counters
+    pub fn counters(&self) -> &Arc<RateLimitCounters> {
+        &self.counters
+    }
+
+    /// Checks the packet against all limits and takes the tokens,
+    /// the strictest action of all exceeded limits is returned
+    pub fn check(&mut self, pkt: &[u8]) -> RateLimitDecision {
+        self.check_at(pkt, Instant::now())
+    }
+
+    fn check_at(&mut self, pkt: &[u8], now: Instant) -> RateLimitDecision {
+        let opcode = pkt.get(..2).map(|op| u16::from_le_bytes([op[0], op[1]]));
+        let opcode_bucket = match (opcode, self.config.opcode_hook.as_ref()) {
+            (Some(op), Some(hook)) => match self.opcodes.entry(op) {
+                Entry::Occupied(entry) => Some(entry.into_mut()),
+                Entry::Vacant(entry) => {
+                    hook(op).map(|limit| entry.insert(TokenBucket::new(limit, now)))
+                }
+            }
+            .map(|bucket| (bucket, RateLimitKind::OpCode(op))),
+            _ => None,
+        };
+
+        let mut buckets = [
+            self.packets
+                .as_mut()
+                .map(|bucket| (bucket, RateLimitKind::Packets)),
+            self.bytes
+                .as_mut()
+                .map(|bucket| (bucket, RateLimitKind::Bytes)),
+            opcode_bucket,
+        ];
+        let cost = |kind: RateLimitKind| match kind {
+            RateLimitKind::Bytes => pkt.len() as f64,
+            _ => 1.,
+        };
+
+        let mut delay: Option<Duration> = None;
+        let mut drop = false;
+        for (bucket, kind) in buckets.iter_mut().flatten() {
+            bucket.refill(now);
+            let Some(wait) = bucket.wait_time(cost(*kind)) else {
+                continue;
+            };
+            match bucket.limit.action {
+                RateLimitAction::Close => {
+                    RateLimitCounters::inc(&self.counters.closed);
+                    return RateLimitDecision::Close(*kind);
+                }
+                RateLimitAction::Drop => drop = true,
+                RateLimitAction::Delay => delay = Some(delay.map_or(wait, |d| d.max(wait))),
+            }
+        }
+
+        if drop {
+            RateLimitCounters::inc(&self.counters.dropped);
+            return RateLimitDecision::Drop;
+        }
+
+        for (bucket, kind) in buckets.iter_mut().flatten() {
+            bucket.take(cost(*kind));
+        }
+
+        match delay {
+            Some(delay) => {
+                RateLimitCounters::inc(&self.counters.delayed);
+                RateLimitDecision::Delay(delay)
+            }
+            None => {
+                RateLimitCounters::inc(&self.counters.passed);
+                RateLimitDecision::Pass
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::SharedCryptoContext;
+    use tokio::time::Instant;
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::{
+        codec::{
+            legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda},
+            ShroomCodec,
+        },
+        NetError, Packet,
+    };
+
+    use super::{
+        Limit, RateLimitAction, RateLimitConfig, RateLimitDecision, RateLimitKind, RateLimiter,
+    };
+
+    const PORT: u16 = 1738;
+
+    fn codec() -> LegacyCodecNoShanda<turmoil::net::TcpStream> {
+        LegacyCodecNoShanda::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    #[test]
+    fn limiter() {
+        let cfg = RateLimitConfig::default()
+            .with_packets(Limit::new(10, 2))
+            .with_bytes(Limit::new(100, 100).with_action(RateLimitAction::Drop))
+            .with_opcode_limits(|op| {
+                (op == 5).then(|| Limit::new(1, 1).with_action(RateLimitAction::Close))
+            });
+        let mut limiter = RateLimiter::new(cfg);
+        let t = Instant::now();
+
+        assert_eq!(limiter.check_at(&[1, 0], t), RateLimitDecision::Pass);
+        assert_eq!(limiter.check_at(&[1, 0], t), RateLimitDecision::Pass);
+        // Burst is exhausted, 1 token is refilled after 100ms
+        assert_eq!(
+            limiter.check_at(&[1, 0], t),
+            RateLimitDecision::Delay(Duration::from_millis(100))
+        );
+        let t = t + Duration::from_millis(300);
+        assert_eq!(limiter.check_at(&[1, 0], t), RateLimitDecision::Pass);
+
+        // Exceeds the bytes
+        assert_eq!(limiter.check_at(&[0; 101], t), RateLimitDecision::Drop);
+
+        assert_eq!(limiter.check_at(&[5, 0], t), RateLimitDecision::Pass);
+        assert_eq!(
+            limiter.check_at(&[5, 0], t),
+            RateLimitDecision::Close(RateLimitKind::OpCode(5))
+        );
+
+        let stats = limiter.counters().stats();
+        assert_eq!(
+            (stats.passed, stats.delayed, stats.dropped, stats.closed),
+            (4, 1, 1, 1)
+        );
+    }
+
+    #[test]
+    fn unlimited_opcodes() {
+        let cfg =
+            RateLimitConfig::default().with_opcode_limits(|op| (op == 5).then(|| Limit::new(1, 1)));
+        let mut limiter = RateLimiter::new(cfg);
+        let t = Instant::now();
+
+        // Opcodes without a limit are not cached
+        for op in 0..=u16::MAX {
+            limiter.check_at(&op.to_le_bytes(), t);
+        }
+        assert_eq!(limiter.opcodes.len(), 1);
+    }
+
+    #[test]
+    fn flood() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            let socket = listener.accept().await?.0;
+            let cfg = RateLimitConfig::default()
+                .with_packets(Limit::new(1, 5).with_action(RateLimitAction::Drop))
+                .with_opcode_limits(|op| {
+                    (op == 9).then(|| Limit::new(1, 1).with_action(RateLimitAction::Close))
+                });
+            let mut sess = codec()
+                .create_server(socket)
+                .await?
+                .with_rate_limit(Some(RateLimiter::new(cfg)));
+            let counters = sess.rate_limit_counters().unwrap().clone();
+
+            // Only the burst passes, the opcode limit closes the stream
+            for i in 0..5u8 {
+                assert_eq!(&sess.next().await.unwrap()?[..], &[1, 0, i]);
+            }
+            assert_eq!(&sess.next().await.unwrap()?[..], &[9, 0]);
+            assert!(matches!(
+                sess.next().await.unwrap(),
+                Err(NetError::RateLimited(RateLimitKind::OpCode(9)))
+            ));
+
+            let stats = counters.stats();
+            assert_eq!((stats.passed, stats.dropped, stats.closed), (6, 5, 1));
+            Ok(())
+        });
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            for i in 0..10u8 {
+                sess.send(&[1, 0, i][..]).await?;
+            }
+            // Wait for a token to be refilled
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            sess.send(Packet::from_static(&[9, 0])).await?;
+            sess.send(Packet::from_static(&[9, 0])).await?;
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+}
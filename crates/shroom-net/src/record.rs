@@ -0,0 +1,671 @@
+use std::{
+    io::{self, Read, Write},
+    ops::{Deref, Range},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use shroom_pkt::Packet;
+use tokio::task::JoinHandle;
+
+use crate::{codec::ShroomCodec, NetError, NetResult, ShroomStream};
+
+const LOG_MAGIC: &[u8; 4] = b"SHRL";
+const LOG_VERSION: u8 = 1;
+// dir(u8) + timestamp(u64) + len(u16)
+const ENTRY_HEADER_LEN: usize = 1 + 8 + 2;
+
+fn invalid_log(msg: &'static str) -> NetError {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+/// Appends an entry with the time since `start` to the buffer
+fn write_entry(
+    buf: &mut Vec<u8>,
+    start: Instant,
+    direction: Direction,
+    data: &[u8],
+) -> NetResult<()> {
+    let len = u16::try_from(data.len()).map_err(|_| NetError::FrameSize(data.len()))?;
+    let ts = start.elapsed().as_micros() as u64;
+
+    let mut hdr = [0; ENTRY_HEADER_LEN];
+    hdr[0] = direction as u8;
+    hdr[1..9].copy_from_slice(&ts.to_le_bytes());
+    hdr[9..].copy_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&hdr);
+    buf.extend_from_slice(data);
+    Ok(())
+}
+
+/// Side of the session, which was recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordSide {
+    Client = 0,
+    Server = 1,
+}
+
+/// Direction of a packet, relative to the recording side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Send = 0,
+    Recv = 1,
+}
+
+/// Recorded packet
+#[derive(Debug, Clone)]
+pub struct RecordEntry {
+    pub direction: Direction,
+    /// Time since the start of the recording
+    pub timestamp: Duration,
+    pub data: Packet,
+}
+
+impl RecordEntry {
+    /// Checks whether the packet was sent by the client
+    pub fn is_client_packet(&self, side: RecordSide) -> bool {
+        matches!(
+            (side, self.direction),
+            (RecordSide::Client, Direction::Send) | (RecordSide::Server, Direction::Recv)
+        )
+    }
+}
+
+/// Writes decrypted packets onto a session log, the entries are
+/// buffered in memory and only written to the writer by `flush`.
+/// The writes are blocking, `RecordedStream` runs them on the blocking thread pool
+///
+/// Format: magic, version(u8), side(u8) followed by
+/// entries of direction(u8), timestamp in us(u64), len(u16) and the data
+#[derive(Debug)]
+pub struct SessionLogWriter<W> {
+    w: W,
+    buf: Vec<u8>,
+    start: Instant,
+}
+
+impl<W: Write> SessionLogWriter<W> {
+    /// Creates a new log and writes the header
+    pub fn new(mut w: W, side: RecordSide) -> NetResult<Self> {
+        w.write_all(LOG_MAGIC)?;
+        w.write_all(&[LOG_VERSION, side as u8])?;
+        Ok(Self {
+            w,
+            buf: Vec::new(),
+            start: Instant::now(),
+        })
+    }
+
+    /// Records a packet into the buffer
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> NetResult<()> {
+        write_entry(&mut self.buf, self.start, direction, data)
+    }
+
+    /// Number of buffered bytes, which were not written yet
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Writes the buffered entries and flushes the underlying writer
+    pub fn flush(&mut self) -> NetResult<()> {
+        if !self.buf.is_empty() {
+            self.w.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(self.w.flush()?)
+    }
+
+    /// Flushes the log and returns the writer
+    pub fn into_inner(mut self) -> NetResult<W> {
+        self.flush()?;
+        Ok(self.w)
+    }
+}
+
+/// Reads the entries of a session log
+#[derive(Debug)]
+pub struct SessionLogReader<R> {
+    r: R,
+    side: RecordSide,
+}
+
+impl<R: Read> SessionLogReader<R> {
+    /// Opens the log by reading the header
+    pub fn new(mut r: R) -> NetResult<Self> {
+        let mut hdr = [0; 6];
+        r.read_exact(&mut hdr)?;
+        if hdr[..4] != LOG_MAGIC[..] || hdr[4] != LOG_VERSION {
+            return Err(invalid_log("invalid session log header"));
+        }
+        let side = match hdr[5] {
+            0 => RecordSide::Client,
+            1 => RecordSide::Server,
+            _ => return Err(invalid_log("invalid session log side")),
+        };
+
+        Ok(Self { r, side })
+    }
+
+    /// Side which recorded the session
+    pub fn side(&self) -> RecordSide {
+        self.side
+    }
+
+    /// Reads the next entry, `None` at the end of the log
+    pub fn read_entry(&mut self) -> NetResult<Option<RecordEntry>> {
+        let mut hdr = [0; ENTRY_HEADER_LEN];
+        // Check for a clean end of the log
+        if self.r.read(&mut hdr[..1])? == 0 {
+            return Ok(None);
+        }
+        self.r.read_exact(&mut hdr[1..])?;
+
+        let direction = match hdr[0] {
+            0 => Direction::Send,
+            1 => Direction::Recv,
+            _ => return Err(invalid_log("invalid entry direction")),
+        };
+        let ts = u64::from_le_bytes(hdr[1..9].try_into().expect("timestamp"));
+        let len = u16::from_le_bytes(hdr[9..].try_into().expect("len")) as usize;
+        let mut data = vec![0; len];
+        self.r.read_exact(&mut data)?;
+
+        Ok(Some(RecordEntry {
+            direction,
+            timestamp: Duration::from_micros(ts),
+            data: Packet::from(bytes::Bytes::from(data)),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for SessionLogReader<R> {
+    type Item = NetResult<RecordEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+type FlushTask<W> = JoinHandle<(SessionLogWriter<W>, NetResult<()>)>;
+
+/// Tap around a `ShroomStream`, which records every packet
+/// which is sent or received onto a session log.
+/// The log is only written when the sink is flushed or closed,
+/// the blocking writes run on the blocking thread pool
+pub struct RecordedStream<C: ShroomCodec, W> {
+    inner: ShroomStream<C>,
+    /// Entries, which were recorded since the last flush started
+    buf: Vec<u8>,
+    start: Instant,
+    /// The log is moved into the flush task, while It's running
+    log: Option<SessionLogWriter<W>>,
+    flush: Option<FlushTask<W>>,
+}
+
+impl<C: ShroomCodec, W: Write + Send + 'static> RecordedStream<C, W> {
+    /// Creates a tap, which records onto the log
+    pub fn new(inner: ShroomStream<C>, log: SessionLogWriter<W>) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            start: log.start,
+            log: Some(log),
+            flush: None,
+        }
+    }
+
+    /// Gets the inner stream
+    pub fn get_mut(&mut self) -> &mut ShroomStream<C> {
+        &mut self.inner
+    }
+
+    /// Removes the tap and returns the stream and the log,
+    /// entries which were not flushed yet remain buffered in the log
+    pub async fn into_inner(mut self) -> NetResult<(ShroomStream<C>, SessionLogWriter<W>)> {
+        futures::future::poll_fn(|cx| self.poll_flush_done(cx)).await?;
+        let mut log = self.log.take().expect("idle log");
+        log.buf.append(&mut self.buf);
+        Ok((self.inner, log))
+    }
+
+    /// Waits for the running flush task
+    fn poll_flush_done(&mut self, cx: &mut Context<'_>) -> Poll<NetResult<()>> {
+        let Some(task) = self.flush.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+
+        let res = ready!(task.poll_unpin(cx));
+        self.flush = None;
+        let (log, res) = match res {
+            Ok(res) => res,
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(err) => return Poll::Ready(Err(io::Error::other(err).into())),
+        };
+        self.log = Some(log);
+        Poll::Ready(res)
+    }
+
+    /// Writes the recorded entries onto the log
+    fn poll_flush_log(&mut self, cx: &mut Context<'_>) -> Poll<NetResult<()>> {
+        ready!(self.poll_flush_done(cx))?;
+        let log = self.log.as_mut().expect("idle log");
+        if self.buf.is_empty() && log.pending() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut log = self.log.take().expect("idle log");
+        log.buf.append(&mut self.buf);
+        self.flush = Some(tokio::task::spawn_blocking(move || {
+            let res = log.flush();
+            (log, res)
+        }));
+        self.poll_flush_done(cx)
+    }
+}
+
+impl<C: ShroomCodec, W: Unpin> Stream for RecordedStream<C, W> {
+    type Item = NetResult<Packet>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let pkt = match this.inner.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(pkt))) => pkt,
+            res => return res,
+        };
+
+        let res = write_entry(&mut this.buf, this.start, Direction::Recv, &pkt);
+        Poll::Ready(Some(res.map(|_| pkt)))
+    }
+}
+
+impl<C, W, T> Sink<T> for RecordedStream<C, W>
+where
+    C: ShroomCodec,
+    W: Write + Send + Unpin + 'static,
+    T: Deref<Target = [u8]>,
+{
+    type Error = NetError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        SinkExt::<T>::poll_ready_unpin(&mut self.get_mut().inner, cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        // The item is moved into the sink, so the entry is
+        // recorded first and removed again If the send failed
+        let mark = this.buf.len();
+        write_entry(&mut this.buf, this.start, Direction::Send, &item)?;
+        this.inner.start_send_unpin(item).inspect_err(|_| {
+            this.buf.truncate(mark);
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_log(cx))?;
+        SinkExt::<T>::poll_flush_unpin(&mut this.inner, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_log(cx))?;
+        SinkExt::<T>::poll_close_unpin(&mut this.inner, cx)
+    }
+}
+
+/// Rule to ignore volatile bytes like timestamps when comparing packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoreRule {
+    /// Opcode the rule applies to, `None` applies to every packet
+    pub opcode: Option<u16>,
+    /// Byte range to ignore, including the 2 opcode bytes
+    pub range: Range<usize>,
+}
+
+impl IgnoreRule {
+    /// Ignores the range for the given opcode
+    pub fn new(opcode: u16, range: Range<usize>) -> Self {
+        Self {
+            opcode: Some(opcode),
+            range,
+        }
+    }
+}
+
+/// Difference between an expected and actual packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffKind {
+    /// No packet was received
+    Missing,
+    /// Packet has a different opcode
+    OpCode {
+        expected: Option<u16>,
+        actual: Option<u16>,
+    },
+    /// Packet has the same opcode but different bytes
+    Payload {
+        opcode: Option<u16>,
+        ranges: Vec<Range<usize>>,
+    },
+}
+
+/// Mismatch of a server response during a replay
+#[derive(Debug, Clone)]
+pub struct PacketDiff {
+    /// Index of the entry in the log
+    pub index: usize,
+    pub expected: Packet,
+    pub actual: Option<Packet>,
+    pub kind: DiffKind,
+}
+
+/// Report of a replay
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub sent: usize,
+    pub received: usize,
+    pub diffs: Vec<PacketDiff>,
+}
+
+impl ReplayReport {
+    /// Whether all responses matched the recording
+    pub fn is_match(&self) -> bool {
+        self.diffs.is_empty()
+    }
+}
+
+fn opcode(data: &[u8]) -> Option<u16> {
+    data.get(..2)
+        .map(|op| u16::from_le_bytes(op.try_into().expect("opcode")))
+}
+
+/// Replays the client side of a recorded session against a server
+#[derive(Debug, Clone)]
+pub struct ReplayDriver {
+    rules: Vec<IgnoreRule>,
+    recv_timeout: Duration,
+    keep_timing: bool,
+}
+
+impl Default for ReplayDriver {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            recv_timeout: Duration::from_secs(5),
+            keep_timing: false,
+        }
+    }
+}
+
+impl ReplayDriver {
+    /// Adds a rule to ignore bytes
+    pub fn ignore(mut self, rule: IgnoreRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Sets the time to wait for an expected response
+    pub fn with_recv_timeout(mut self, timeout: Duration) -> Self {
+        self.recv_timeout = timeout;
+        self
+    }
+
+    /// Sends the client packets with the recorded timing
+    pub fn with_timing(mut self, keep_timing: bool) -> Self {
+        self.keep_timing = keep_timing;
+        self
+    }
+
+    /// Compares the packets, returns `None` if they match
+    pub fn diff(&self, expected: &[u8], actual: &[u8]) -> Option<DiffKind> {
+        let op = opcode(expected);
+        if op != opcode(actual) {
+            return Some(DiffKind::OpCode {
+                expected: op,
+                actual: opcode(actual),
+            });
+        }
+
+        let ignored = |ix: usize| {
+            self.rules
+                .iter()
+                .any(|r| r.opcode.is_none_or(|o| Some(o) == op) && r.range.contains(&ix))
+        };
+
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for ix in 0..expected.len().max(actual.len()) {
+            if expected.get(ix) == actual.get(ix) || ignored(ix) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end == ix => last.end += 1,
+                _ => ranges.push(ix..ix + 1),
+            }
+        }
+
+        (!ranges.is_empty()).then_some(DiffKind::Payload { opcode: op, ranges })
+    }
+
+    /// Replays the entries, the client packets are sent and the responses
+    /// of the server are compared with the recorded ones
+    pub async fn replay<C: ShroomCodec>(
+        &self,
+        stream: &mut ShroomStream<C>,
+        side: RecordSide,
+        entries: impl IntoIterator<Item = RecordEntry>,
+    ) -> NetResult<ReplayReport> {
+        let mut report = ReplayReport::default();
+        let start = tokio::time::Instant::now();
+
+        for (index, entry) in entries.into_iter().enumerate() {
+            if entry.is_client_packet(side) {
+                if self.keep_timing {
+                    tokio::time::sleep_until(start + entry.timestamp).await;
+                }
+                stream.send(entry.data).await?;
+                report.sent += 1;
+                continue;
+            }
+
+            let actual = match tokio::time::timeout(self.recv_timeout, stream.next()).await {
+                Ok(Some(pkt)) => Some(pkt?),
+                Ok(None) | Err(_) => None,
+            };
+            let kind = match actual {
+                Some(ref actual) => {
+                    report.received += 1;
+                    self.diff(&entry.data, actual)
+                }
+                None => Some(DiffKind::Missing),
+            };
+
+            if let Some(kind) = kind {
+                report.diffs.push(PacketDiff {
+                    index,
+                    expected: entry.data,
+                    actual,
+                    kind,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicU8, Ordering},
+            Arc,
+        },
+    };
+
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::SharedCryptoContext;
+    use shroom_pkt::Packet;
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::codec::{
+        legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda, MAX_PACKET_LEN},
+        memory::MemoryTransport,
+        ShroomCodec,
+    };
+
+    use super::*;
+
+    const PORT: u16 = 1738;
+
+    fn codec() -> LegacyCodecNoShanda<turmoil::net::TcpStream> {
+        LegacyCodecNoShanda::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    fn memory_codec() -> LegacyCodecNoShanda<MemoryTransport> {
+        LegacyCodecNoShanda::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    #[test]
+    fn log_read_write() -> anyhow::Result<()> {
+        let mut log = SessionLogWriter::new(Vec::new(), RecordSide::Client)?;
+        log.record(Direction::Send, &[1, 0, 2])?;
+        log.record(Direction::Recv, &[])?;
+        assert_eq!(log.pending(), 2 * ENTRY_HEADER_LEN + 3);
+
+        let data = log.into_inner()?;
+        let reader = SessionLogReader::new(data.as_slice())?;
+        assert_eq!(reader.side(), RecordSide::Client);
+        let entries = reader.collect::<NetResult<Vec<_>>>()?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].direction, Direction::Send);
+        assert_eq!(&entries[0].data[..], &[1, 0, 2]);
+        assert_eq!(entries[1].direction, Direction::Recv);
+        assert!(entries[1].data.is_empty());
+
+        assert!(SessionLogReader::new(&b"SHRX\x01\x00"[..]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_send() -> anyhow::Result<()> {
+        let (client, server) =
+            MemoryTransport::pair("192.168.0.1:56324".parse()?, "10.0.0.1:8484".parse()?);
+        let server = tokio::spawn(async move { memory_codec().create_server(server).await });
+        let sess = memory_codec().create_client(client).await?;
+        let _server = server.await??;
+
+        let log = SessionLogWriter::new(Vec::new(), RecordSide::Client)?;
+        let mut sess = RecordedStream::new(sess, log);
+        // Exceeds the max packet length, so the encoder rejects It
+        assert!(sess.send(vec![0; MAX_PACKET_LEN + 1]).await.is_err());
+        sess.send(vec![1, 0]).await?;
+
+        let log = sess.into_inner().await?.1.into_inner()?;
+        let entries = SessionLogReader::new(log.as_slice())?.collect::<NetResult<Vec<_>>>()?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(&entries[0].data[..], &[1, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn diff() {
+        let driver = ReplayDriver::default().ignore(IgnoreRule::new(1, 2..4));
+        assert_eq!(driver.diff(&[1, 0, 1, 1, 5], &[1, 0, 2, 2, 5]), None);
+        assert_eq!(
+            driver.diff(&[1, 0, 1], &[2, 0, 1]),
+            Some(DiffKind::OpCode {
+                expected: Some(1),
+                actual: Some(2)
+            })
+        );
+        assert_eq!(
+            driver.diff(&[2, 0, 1, 1, 5], &[2, 0, 1, 2, 6, 7]),
+            Some(DiffKind::Payload {
+                opcode: Some(2),
+                ranges: vec![3..6]
+            })
+        );
+    }
+
+    #[test]
+    fn record_replay() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        // Echo server, which puts a counter into the 3rd byte
+        sim.host("server", || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            let counter = Arc::new(AtomicU8::new(0));
+            loop {
+                let socket = listener.accept().await?.0;
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut sess = codec().create_server(socket).await?;
+                    while let Some(Ok(pkt)) = sess.next().await {
+                        let mut data = pkt.to_vec();
+                        data[2] = counter.fetch_add(1, Ordering::SeqCst);
+                        sess.send(data.as_slice()).await?;
+                    }
+                    NetResult::Ok(())
+                });
+            }
+        });
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let sess = codec().create_client(socket).await?;
+            let log = SessionLogWriter::new(Vec::new(), RecordSide::Client)?;
+            let mut sess = RecordedStream::new(sess, log);
+            for data in [[1u8, 0, 0, 1], [2, 0, 0, 2]] {
+                sess.send(Packet::from(bytes::Bytes::copy_from_slice(&data)))
+                    .await?;
+                sess.next().await.unwrap()?;
+            }
+            let log = sess.into_inner().await?.1.into_inner()?;
+            let entries = SessionLogReader::new(log.as_slice())?.collect::<NetResult<Vec<_>>>()?;
+            assert_eq!(entries.len(), 4);
+
+            // Counter differs from the recording
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            let report = ReplayDriver::default()
+                .replay(&mut sess, RecordSide::Client, entries.clone())
+                .await?;
+            assert_eq!(report.sent, 2);
+            assert_eq!(report.received, 2);
+            assert_eq!(report.diffs.len(), 2);
+            assert_eq!(
+                report.diffs[0].kind,
+                DiffKind::Payload {
+                    opcode: Some(1),
+                    ranges: vec![2..3]
+                }
+            );
+
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            let report = ReplayDriver::default()
+                .ignore(IgnoreRule {
+                    opcode: None,
+                    range: 2..3,
+                })
+                .replay(&mut sess, RecordSide::Client, entries)
+                .await?;
+            assert!(report.is_match());
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+}
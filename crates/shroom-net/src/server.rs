@@ -0,0 +1,530 @@
+use std::{
+    any::Any, future::Future, net::SocketAddr, panic::AssertUnwindSafe, sync::Arc, time::Duration,
+};
+
+use futures::FutureExt;
+use tokio::{
+    sync::watch,
+    task::{JoinError, JoinSet},
+};
+
+#[cfg(feature = "tracing")]
+use crate::trace::{session_span, PacketTracer};
+
+use crate::{
+    codec::ShroomCodec,
+    codec::ShroomTransport,
+    metrics::SharedNetMetrics,
+    ratelimit::{RateLimitConfig, RateLimiter},
+    timeout::SessionTimeouts,
+    NetError, NetResult, ShroomStream,
+};
+
+/// Default time a client has to complete the handshake
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handler, which is called with the peer address and the error of every failed session
+pub type SessionErrorHandler = Arc<dyn Fn(SocketAddr, &NetError) + Send + Sync>;
+
+/// Result of a session task, a panic of the session is caught
+type SessionResult = (SocketAddr, std::thread::Result<NetResult<()>>);
+
+/// Gets the message of a panic payload
+fn panic_msg(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|msg| msg.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+/// Reports the errors of finished sessions
+struct SessionErrors {
+    handler: Option<SessionErrorHandler>,
+    catch_panics: bool,
+}
+
+impl SessionErrors {
+    /// Reports the error of the session, panics are re-raised unless they are caught
+    fn report(&self, res: Result<SessionResult, JoinError>) {
+        let (peer, err) = match res {
+            Ok((_, Ok(Ok(())))) => return,
+            Ok((peer, Ok(Err(err)))) => (peer, err),
+            Ok((_, Err(panic))) if !self.catch_panics => std::panic::resume_unwind(panic),
+            Ok((peer, Err(panic))) => (peer, NetError::SessionPanicked(panic_msg(&*panic))),
+            // Sessions are only aborted after the drain timeout expired
+            Err(_) => return,
+        };
+
+        #[cfg(feature = "tracing")]
+        tracing::warn!(%peer, ?err, "Session failed");
+        if let Some(handler) = self.handler.as_ref() {
+            handler(peer, &err);
+        }
+    }
+}
+
+/// Listener which accepts new transports
+pub trait ShroomListener: Send + 'static {
+    type Transport: ShroomTransport;
+
+    /// Accepts a new transport
+    fn accept(&mut self) -> impl Future<Output = NetResult<(Self::Transport, SocketAddr)>> + Send;
+}
+
+impl ShroomListener for tokio::net::TcpListener {
+    type Transport = tokio::net::TcpStream;
+
+    async fn accept(&mut self) -> NetResult<(Self::Transport, SocketAddr)> {
+        Ok(tokio::net::TcpListener::accept(self).await?)
+    }
+}
+
+#[cfg(test)]
+impl ShroomListener for turmoil::net::TcpListener {
+    type Transport = turmoil::net::TcpStream;
+
+    async fn accept(&mut self) -> NetResult<(Self::Transport, SocketAddr)> {
+        Ok(turmoil::net::TcpListener::accept(self).await?)
+    }
+}
+
+/// Handle to shut down a running server
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    /// Signals the server to stop accepting and all sessions to stop
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Returns whether the shutdown was already signaled
+    pub fn is_shutdown(&self) -> bool {
+        *self.0.borrow()
+    }
+}
+
+/// Shutdown signal passed to every session handler
+#[derive(Debug, Clone)]
+pub struct SessionShutdown(watch::Receiver<bool>);
+
+impl SessionShutdown {
+    /// Returns whether the shutdown was signaled
+    pub fn is_shutdown(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Waits until the shutdown is signaled
+    pub async fn wait(&mut self) {
+        // An error means the server is gone, which is a shutdown aswell
+        let _ = self.0.wait_for(|v| *v).await;
+    }
+}
+
+/// Server which accepts sessions on a listener and runs
+/// an handler for each session in a separate task
+pub struct ShroomServer<C: ShroomCodec, L = tokio::net::TcpListener> {
+    codec: Arc<C>,
+    listener: L,
+    timeouts: SessionTimeouts,
+    rate_limit: Option<RateLimitConfig>,
+    drain_timeout: Option<Duration>,
+    metrics: Option<SharedNetMetrics>,
+    #[cfg(feature = "tracing")]
+    tracer: Option<Arc<PacketTracer>>,
+    error_handler: Option<SessionErrorHandler>,
+    catch_panics: bool,
+    shutdown: ShutdownHandle,
+}
+
+impl<C> ShroomServer<C, tokio::net::TcpListener>
+where
+    C: ShroomCodec<Transport = tokio::net::TcpStream> + 'static,
+{
+    /// Binds a tcp listener on the given address
+    pub async fn bind(codec: C, addr: impl tokio::net::ToSocketAddrs) -> NetResult<Self> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        Ok(Self::new(codec, listener))
+    }
+}
+
+impl<C, L> ShroomServer<C, L>
+where
+    C: ShroomCodec + 'static,
+    L: ShroomListener<Transport = C::Transport>,
+{
+    /// Creates a new server from the codec and listener
+    pub fn new(codec: C, listener: L) -> Self {
+        Self {
+            codec: Arc::new(codec),
+            listener,
+            timeouts: SessionTimeouts::default().with_handshake(DEFAULT_HANDSHAKE_TIMEOUT),
+            rate_limit: None,
+            drain_timeout: None,
+            metrics: None,
+            #[cfg(feature = "tracing")]
+            tracer: None,
+            error_handler: None,
+            catch_panics: false,
+            shutdown: ShutdownHandle(Arc::new(watch::Sender::new(false))),
+        }
+    }
+
+    /// Sets the time a client has to complete the handshake
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.handshake = Some(timeout);
+        self
+    }
+
+    /// Sets the timeouts, which are applied to every session
+    pub fn with_timeouts(mut self, timeouts: SessionTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Sets the rate limits, which are applied to the incoming packets of every session
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limit = Some(config);
+        self
+    }
+
+    /// Sets the time to wait for sessions to finish after a shutdown,
+    /// remaining sessions are aborted afterwards. By default there's no limit
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the metrics, which are notified about the events of every session
+    pub fn with_metrics(mut self, metrics: SharedNetMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Sets the tracer, which logs the packets of every session.
+    /// Sessions always run within a span with the peer address and the handshake
+    #[cfg(feature = "tracing")]
+    pub fn with_tracer(mut self, tracer: Arc<PacketTracer>) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Sets the handler, which is called with the error of every failed session,
+    /// including failed handshakes and timeouts. Errors are also logged with `tracing`
+    pub fn with_error_handler(
+        mut self,
+        handler: impl Fn(SocketAddr, &NetError) + Send + Sync + 'static,
+    ) -> Self {
+        self.error_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Sets whether panics of session handlers are caught, by default they are
+    /// re-raised by `run`. Caught panics are reported as `NetError::SessionPanicked`
+    pub fn with_catch_panics(mut self, catch_panics: bool) -> Self {
+        self.catch_panics = catch_panics;
+        self
+    }
+
+    /// Gets the handle to shut down the server
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Gets the listener
+    pub fn listener(&self) -> &L {
+        &self.listener
+    }
+
+    /// Runs the accept loop until the shutdown is signaled, every accepted
+    /// session is handed to the `handler` after the handshake completed
+    pub async fn run<F, Fut>(mut self, handler: F) -> NetResult<()>
+    where
+        F: Fn(ShroomStream<C>, SessionShutdown) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = NetResult<()>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let mut shutdown = SessionShutdown(self.shutdown.0.subscribe());
+        let mut sessions = JoinSet::new();
+        let errors = SessionErrors {
+            handler: self.error_handler.clone(),
+            catch_panics: self.catch_panics,
+        };
+
+        let res = loop {
+            tokio::select! {
+                _ = shutdown.wait() => break Ok(()),
+                // Reap finished sessions
+                Some(res) = sessions.join_next(), if !sessions.is_empty() => errors.report(res),
+                res = self.listener.accept() => {
+                    let (trans, addr) = match res {
+                        Ok(accepted) => accepted,
+                        Err(NetError::IO(err)) if is_transient_accept_err(&err) => continue,
+                        Err(err) => break Err(err),
+                    };
+
+                    let codec = self.codec.clone();
+                    let handler = handler.clone();
+                    let session_shutdown = shutdown.clone();
+                    let timeouts = self.timeouts;
+                    let rate_limit = self.rate_limit.clone().map(RateLimiter::new);
+                    let metrics = self.metrics.clone();
+                    #[cfg(feature = "tracing")]
+                    let tracer = self.tracer.clone();
+                    let session = async move {
+                        let sess = codec
+                            .create_server_with_metrics(trans, timeouts, metrics)
+                            .await?
+                            .with_rate_limit(rate_limit);
+                        #[cfg(feature = "tracing")]
+                        let sess = match tracer {
+                            Some(tracer) => sess.with_tracer(tracer, tracing::Span::current()),
+                            None => sess,
+                        };
+                        handler(sess, session_shutdown).await
+                    };
+
+                    #[cfg(feature = "tracing")]
+                    let session = tracing::Instrument::instrument(session, session_span(addr));
+                    let session = AssertUnwindSafe(session).catch_unwind();
+                    sessions.spawn(async move { (addr, session.await) });
+                }
+            }
+        };
+
+        // Stop all sessions and wait for them to drain
+        self.shutdown.shutdown();
+        let drain = async {
+            while let Some(res) = sessions.join_next().await {
+                errors.report(res);
+            }
+        };
+        match self.drain_timeout {
+            Some(timeout) => {
+                if tokio::time::timeout(timeout, drain).await.is_err() {
+                    sessions.shutdown().await;
+                }
+            }
+            None => drain.await,
+        }
+
+        res
+    }
+}
+
+/// Errors which only affect the accepted connection, not the listener
+pub(crate) fn is_transient_accept_err(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::Interrupted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use shroom_crypto::SharedCryptoContext;
+    use tokio::{io::AsyncReadExt, sync::mpsc, task::JoinHandle};
+    use turmoil::net::{TcpListener, TcpStream};
+
+    use crate::{
+        codec::{
+            legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda},
+            memory::{MemoryConnector, MemoryListener, MemoryTransport},
+            websocket::WebSocketCodec,
+            ShroomCodec, ShroomTransport,
+        },
+        NetError, NetResult, ShroomStream,
+    };
+
+    use super::{SessionShutdown, ShroomServer, ShutdownHandle};
+
+    const PORT: u16 = 1738;
+
+    type Codec = LegacyCodecNoShanda<turmoil::net::TcpStream>;
+
+    fn codec() -> Codec {
+        LegacyCodecNoShanda::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+    }
+
+    async fn echo<C: ShroomCodec + Unpin>(
+        mut sess: ShroomStream<C>,
+        mut shutdown: SessionShutdown,
+    ) -> NetResult<()> {
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                pkt = sess.next() => match pkt {
+                    Some(Ok(pkt)) => sess.send(pkt).await?,
+                    _ => break,
+                }
+            }
+        }
+        sess.close().await
+    }
+
+    #[test]
+    fn server_shutdown() -> anyhow::Result<()> {
+        let handle: Arc<Mutex<Option<ShutdownHandle>>> = Arc::default();
+        let done = Arc::new(AtomicBool::new(false));
+        let mut sim = turmoil::Builder::new().build();
+
+        let (server_handle, server_done) = (handle.clone(), done.clone());
+        sim.host("server", move || {
+            let (handle, done) = (server_handle.clone(), server_done.clone());
+            async move {
+                let listener =
+                    TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+                let server = ShroomServer::new(codec(), listener);
+                *handle.lock().unwrap() = Some(server.shutdown_handle());
+                server.run(echo).await?;
+                done.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        });
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let mut sess = codec().create_client(socket).await?;
+            sess.send(Bytes::from_static(&[1, 2, 3])).await?;
+            assert_eq!(&sess.next().await.unwrap()?[..], &[1, 2, 3]);
+
+            handle.lock().unwrap().as_ref().unwrap().shutdown();
+            // The session must be closed by the server
+            assert!(sess.next().await.is_none());
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(done.load(Ordering::SeqCst));
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn server_handshake_timeout() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+            let codec = WebSocketCodec::<turmoil::net::TcpStream>::new(http::Uri::from_static(
+                "ws://127.0.0.1",
+            ));
+            ShroomServer::new(codec, listener)
+                .with_handshake_timeout(Duration::from_secs(1))
+                .run(echo)
+                .await?;
+            Ok(())
+        });
+
+        sim.client("client", async move {
+            // Never send the upgrade request, the server must drop the connection
+            let mut socket = TcpStream::connect(("server", PORT)).await?;
+            let mut buf = [0; 1];
+            let n = tokio::time::timeout(Duration::from_secs(5), socket.read(&mut buf)).await??;
+            assert_eq!(n, 0);
+            Ok(())
+        });
+
+        sim.run().unwrap();
+        Ok(())
+    }
+
+    /// Memory server, which reports the errors of all sessions to `errors`
+    struct ErrorServer {
+        connector: MemoryConnector,
+        handle: ShutdownHandle,
+        errors: mpsc::UnboundedReceiver<(SocketAddr, String)>,
+        server: JoinHandle<NetResult<()>>,
+    }
+
+    fn error_server<F, Fut>(catch_panics: bool, handler: F) -> ErrorServer
+    where
+        F: Fn(ShroomStream<LegacyCodecNoShanda<MemoryTransport>>, SessionShutdown) -> Fut
+            + Send
+            + Sync
+            + 'static,
+        Fut: Future<Output = NetResult<()>> + Send + 'static,
+    {
+        let listener = MemoryListener::bind("10.0.0.1:8484".parse().unwrap());
+        let connector = listener.connector();
+        let (tx, errors) = mpsc::unbounded_channel();
+        let server = ShroomServer::new(
+            LegacyCodecNoShanda::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v83(),
+            ),
+            listener,
+        )
+        .with_catch_panics(catch_panics)
+        .with_error_handler(move |peer, err| {
+            let _ = tx.send((peer, err.to_string()));
+        });
+        ErrorServer {
+            connector,
+            handle: server.shutdown_handle(),
+            errors,
+            server: tokio::spawn(server.run(handler)),
+        }
+    }
+
+    async fn connect(connector: &MemoryConnector) -> anyhow::Result<SocketAddr> {
+        let trans = connector.connect()?;
+        let peer = trans.local_addr()?;
+        LegacyCodecNoShanda::<MemoryTransport>::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        )
+        .create_client(trans)
+        .await?;
+        Ok(peer)
+    }
+
+    #[tokio::test]
+    async fn session_errors() -> anyhow::Result<()> {
+        let mut srv = error_server(false, |_, _| async { Err(NetError::InvalidOpCode(0x11)) });
+
+        let peer = connect(&srv.connector).await?;
+        assert_eq!(
+            srv.errors.recv().await,
+            Some((peer, NetError::InvalidOpCode(0x11).to_string()))
+        );
+
+        srv.handle.shutdown();
+        srv.server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_panic() -> anyhow::Result<()> {
+        let srv = error_server(false, |_, _| async { panic!("boom") });
+        connect(&srv.connector).await?;
+        // The panic is re-raised by the server
+        assert!(srv.server.await.unwrap_err().is_panic());
+
+        let mut srv = error_server(true, |_, _| async { panic!("boom") });
+        let peer = connect(&srv.connector).await?;
+        let err = NetError::SessionPanicked("boom".to_string());
+        assert_eq!(srv.errors.recv().await, Some((peer, err.to_string())));
+        srv.handle.shutdown();
+        srv.server.await??;
+        Ok(())
+    }
+}
@@ -1,15 +1,87 @@
-use std::ops::Deref;
+use std::{future::Future, ops::Deref, pin::Pin, sync::Arc, task::Poll, time::Duration};
 
-use crate::{codec::ShroomCodec, NetError, NetResult};
+use crate::{
+    codec::{ShroomCodec, ShroomSink},
+    heartbeat::Heartbeat,
+    metrics::{CountingMsg, SessionStats, SharedNetMetrics, StatsRecorder},
+    ratelimit::{RateLimitCounters, RateLimitDecision, RateLimiter},
+    timeout::TimeoutKind,
+    NetError, NetResult,
+};
+
+#[cfg(feature = "tracing")]
+use crate::trace::{PacketTracer, SessionTracer};
 
 use futures::{SinkExt, StreamExt};
 
-use shroom_pkt::Packet;
+use shroom_pkt::{
+    pkt::{DecodeMessage, EncodeMessage, Message},
+    util::packet_buf::PacketBuf,
+    Packet,
+};
+use tokio::time::{Instant, Sleep};
+
+/// Timer, which expires If no packet was read in time
+struct ReadIdle {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl ReadIdle {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sleep.as_mut().reset(Instant::now() + self.timeout);
+    }
+}
+
+/// Rate limiter with the currently delayed packet
+struct ReadLimit {
+    limiter: RateLimiter,
+    delayed: Option<(Packet, Pin<Box<Sleep>>)>,
+}
+
+/// Outcome of a graceful shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseOutcome {
+    /// Peer closed the connection after the remaining input was read
+    Clean,
+    /// Connection was reset or aborted
+    Reset,
+    /// Peer did not close the connection before the drain timeout expired
+    TimedOut,
+}
+
+/// Checks whether the error was caused by a reset connection
+fn is_reset(err: &NetError) -> bool {
+    let io_err = match err {
+        NetError::IO(err) => err,
+        NetError::Websocket(tokio_websockets::Error::Io(err)) => err,
+        _ => return false,
+    };
+    matches!(
+        io_err.kind(),
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
 
 /// Shroom stream which allows to send and recv packets
 pub struct ShroomStream<C: ShroomCodec> {
-    r: C::Stream,
-    w: C::Sink,
+    pub(crate) r: C::Stream,
+    pub(crate) w: C::Sink,
+    read_idle: Option<ReadIdle>,
+    rate_limit: Option<ReadLimit>,
+    stats: StatsRecorder,
+    #[cfg(feature = "tracing")]
+    tracer: Option<SessionTracer>,
 }
 
 impl<C: ShroomCodec, T: Deref<Target = [u8]>> futures::Sink<T> for ShroomStream<C> {
@@ -23,7 +95,11 @@ impl<C: ShroomCodec, T: Deref<Target = [u8]>> futures::Sink<T> for ShroomStream<
     }
 
     fn start_send(mut self: std::pin::Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
-        self.w.start_send_unpin(item.deref())
+        let res = self.w.start_send_unpin(item.deref());
+        if res.is_ok() {
+            self.trace_out(item.deref());
+        }
+        self.record_sent(res, item.len())
     }
 
     fn poll_flush(
@@ -45,10 +121,53 @@ impl<C: ShroomCodec> futures::Stream for ShroomStream<C> {
     type Item = NetResult<Packet>;
 
     fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.r.poll_next_unpin(cx)
+        let this = self.get_mut();
+        if let Some(limit) = this.rate_limit.as_mut()
+            && let Some((_, sleep)) = limit.delayed.as_mut()
+        {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let (pkt, _) = limit.delayed.take().expect("delayed");
+            return Poll::Ready(Some(Ok(pkt)));
+        }
+
+        while let Poll::Ready(item) = this.r.poll_next_unpin(cx) {
+            if let Some(idle) = this.read_idle.as_mut() {
+                idle.reset();
+            }
+            this.record_received(item.as_ref());
+
+            let (Some(Ok(pkt)), Some(limit)) = (item.as_ref(), this.rate_limit.as_mut()) else {
+                return Poll::Ready(item);
+            };
+            match limit.limiter.check(pkt) {
+                RateLimitDecision::Pass => return Poll::Ready(item),
+                RateLimitDecision::Drop => continue,
+                RateLimitDecision::Close(kind) => {
+                    return Poll::Ready(Some(Err(NetError::RateLimited(kind))));
+                }
+                RateLimitDecision::Delay(delay) => {
+                    let mut sleep = Box::pin(tokio::time::sleep(delay));
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        return Poll::Ready(item);
+                    }
+                    limit.delayed = item.and_then(Result::ok).map(|pkt| (pkt, sleep));
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        if let Some(idle) = this.read_idle.as_mut()
+            && idle.sleep.as_mut().poll(cx).is_ready()
+        {
+            idle.reset();
+            return Poll::Ready(Some(Err(NetError::Timeout(TimeoutKind::ReadIdle))));
+        }
+        Poll::Pending
     }
 }
 
@@ -65,7 +184,242 @@ where
     /// Create a new session from the `io` and
     pub fn new(w: C::Sink, r: C::Stream) -> Self {
         //let (r, w) = io.split();
-        Self { r, w }
+        Self {
+            r,
+            w,
+            read_idle: None,
+            rate_limit: None,
+            stats: StatsRecorder::default(),
+            #[cfg(feature = "tracing")]
+            tracer: None,
+        }
+    }
+
+    /// Gets the statistics of this session
+    pub fn stats(&self) -> &SessionStats {
+        self.stats.stats()
+    }
+
+    /// Sets the metrics, which are notified about the events of this session
+    pub fn with_metrics(mut self, metrics: Option<SharedNetMetrics>) -> Self {
+        if let Some(metrics) = metrics {
+            self.stats.set_metrics(metrics);
+        }
+        self
+    }
+
+    /// Records the duration of the handshake
+    pub(crate) fn set_handshake_duration(&mut self, duration: Duration) {
+        self.stats.handshake(duration);
+    }
+
+    /// Sets the tracer, which logs the packets of this session within the span
+    #[cfg(feature = "tracing")]
+    pub fn with_tracer(mut self, tracer: Arc<PacketTracer>, span: tracing::Span) -> Self {
+        self.tracer = Some(SessionTracer::new(tracer, span));
+        self
+    }
+
+    /// Records a received packet or the error of the read
+    fn record_received(&mut self, item: Option<&NetResult<Packet>>) {
+        match item {
+            Some(Ok(pkt)) => {
+                self.stats.received(pkt.len());
+                #[cfg(feature = "tracing")]
+                if let Some(tracer) = self.tracer.as_ref() {
+                    tracer.packet_in(pkt);
+                }
+            }
+            Some(Err(err)) => {
+                self.stats.error(err);
+                #[cfg(feature = "tracing")]
+                if let Some(tracer) = self.tracer.as_ref() {
+                    tracer.error(err);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Whether sent packets are logged by the tracer
+    fn traces_out(&self) -> bool {
+        #[cfg(feature = "tracing")]
+        return self.tracer.as_ref().is_some_and(SessionTracer::enabled);
+        #[cfg(not(feature = "tracing"))]
+        false
+    }
+
+    /// Logs the packet, which was accepted by the sink
+    fn trace_out(&self, _data: &[u8]) {
+        #[cfg(feature = "tracing")]
+        if let Some(tracer) = self.tracer.as_ref() {
+            tracer.packet_out(_data);
+        }
+    }
+
+    /// Records a sent packet or the error of the send
+    fn record_sent(&mut self, res: NetResult<()>, len: usize) -> NetResult<()> {
+        match &res {
+            Ok(()) => self.stats.sent(len),
+            Err(err) => self.stats.error(err),
+        }
+        res
+    }
+
+    /// Starts sending the message and records Its encoded length
+    fn start_send_msg<T: EncodeMessage>(&mut self, msg: T) -> NetResult<()> {
+        // Encode the message upfront only If It is logged afterwards
+        if self.traces_out() {
+            let msg = match msg.to_message() {
+                Ok(msg) => msg,
+                Err(err) => return self.record_sent(Err(err.into()), 0),
+            };
+            let res = SinkExt::<&[u8]>::start_send_unpin(&mut self.w, &msg);
+            if res.is_ok() {
+                self.trace_out(&msg);
+            }
+            return self.record_sent(res, msg.len());
+        }
+
+        let mut len = 0;
+        let res = Pin::new(&mut self.w).start_send_msg(CountingMsg { msg, len: &mut len });
+        self.record_sent(res, len)
+    }
+
+    /// Sets the rate limiter for incoming packets
+    pub fn with_rate_limit(mut self, limiter: Option<RateLimiter>) -> Self {
+        self.set_rate_limit(limiter);
+        self
+    }
+
+    /// Sets the rate limiter for incoming packets, `None` disables It
+    pub fn set_rate_limit(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limit = limiter.map(|limiter| ReadLimit {
+            limiter,
+            delayed: None,
+        });
+    }
+
+    /// Gets the counters of the rate limiter
+    pub fn rate_limit_counters(&self) -> Option<&Arc<RateLimitCounters>> {
+        self.rate_limit
+            .as_ref()
+            .map(|limit| limit.limiter.counters())
+    }
+
+    /// Sets the read idle timeout, after which the stream yields a
+    /// `NetError::Timeout` If no packet was received
+    pub fn with_read_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.set_read_idle_timeout(timeout);
+        self
+    }
+
+    /// Sets the read idle timeout, `None` disables It
+    pub fn set_read_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_idle = timeout.map(ReadIdle::new);
+    }
+
+    /// Waits until the sink is ready to accept the next item
+    async fn ready(&mut self) -> NetResult<()> {
+        futures::future::poll_fn(|cx| SinkExt::<&[u8]>::poll_ready_unpin(&mut self.w, cx)).await
+    }
+
+    /// Sends the message, which is encoded directly into the write buffer of the codec
+    pub async fn send_msg<T: EncodeMessage>(&mut self, msg: T) -> NetResult<()> {
+        self.ready().await?;
+        self.start_send_msg(msg)?;
+        SinkExt::<&[u8]>::flush(&mut self.w).await
+    }
+
+    /// Sends the owned packet, sinks which can use the payload directly avoid copying It
+    pub async fn send_packet(&mut self, pkt: Packet) -> NetResult<()> {
+        self.ready().await?;
+        let len = pkt.len();
+        // Cloning only increments the ref count of the payload
+        let traced = self.traces_out().then(|| pkt.clone());
+        let res = Pin::new(&mut self.w).start_send_packet(pkt);
+        if let (Ok(()), Some(pkt)) = (&res, traced) {
+            self.trace_out(&pkt);
+        }
+        self.record_sent(res, len)?;
+        SinkExt::<&[u8]>::flush(&mut self.w).await
+    }
+
+    /// Sends all packets and flushes once afterwards, for the legacy codec the
+    /// packets are encrypted into one contiguous write buffer
+    pub async fn send_batch<T: Deref<Target = [u8]>>(
+        &mut self,
+        pkts: impl IntoIterator<Item = T>,
+    ) -> NetResult<()> {
+        for pkt in pkts {
+            self.ready().await?;
+            let res = SinkExt::<&[u8]>::start_send_unpin(&mut self.w, pkt.deref());
+            if res.is_ok() {
+                self.trace_out(pkt.deref());
+            }
+            self.record_sent(res, pkt.len())?;
+        }
+        SinkExt::<&[u8]>::flush(&mut self.w).await
+    }
+
+    /// Sends all messages and flushes once afterwards,
+    /// like `send_msg` they are encoded directly into the write buffer
+    pub async fn send_msg_batch<T: EncodeMessage>(
+        &mut self,
+        msgs: impl IntoIterator<Item = T>,
+    ) -> NetResult<()> {
+        for msg in msgs {
+            self.ready().await?;
+            self.start_send_msg(msg)?;
+        }
+        SinkExt::<&[u8]>::flush(&mut self.w).await
+    }
+
+    /// Sends all packets of the buffer and flushes once afterwards
+    pub async fn send_packet_buf(&mut self, buf: &PacketBuf) -> NetResult<()> {
+        self.send_batch(buf.packets()).await
+    }
+
+    /// Receives the next message, a closed stream results in an `UnexpectedEof` error
+    pub async fn recv_msg(&mut self) -> NetResult<Message> {
+        let pkt = self.next().await.ok_or_else(|| {
+            NetError::IO(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+        })??;
+        Ok(pkt.try_into()?)
+    }
+
+    /// Receives the next message and decodes It as `T`
+    pub async fn recv_as<T>(&mut self) -> NetResult<T>
+    where
+        T: for<'de> DecodeMessage<'de>,
+    {
+        let msg = self.recv_msg().await?;
+        // Decode failures are logged within the session span
+        #[cfg(feature = "tracing")]
+        let _span = self.tracer.as_ref().map(|tracer| tracer.span().enter());
+        Ok(T::decode_message(&msg)?)
+    }
+
+    /// Receives the next packet, while sending pings with the heartbeat
+    /// Pongs are consumed and not returned
+    pub async fn next_with_heartbeat(&mut self, hb: &mut Heartbeat) -> Option<NetResult<Packet>> {
+        loop {
+            tokio::select! {
+                pkt = self.next() => match pkt {
+                    Some(Ok(pkt)) if hb.on_packet(&pkt) => continue,
+                    pkt => return pkt,
+                },
+                ping = hb.tick() => {
+                    let res = match ping {
+                        Ok(ping) => self.send(ping).await,
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = res {
+                        return Some(Err(err));
+                    }
+                }
+            }
+        }
     }
 
     /// Splits the stream into write and read half references
@@ -78,35 +432,95 @@ where
         (self.w, self.r)
     }
 
-    /// Returns the remote address of the underlying socket
-    pub async fn close(mut self) -> NetResult<()> {
-        //TODO close read half
-        self.w.close().await?;
-        //TODO self.w.0.close().await?;
+    /// Closes the session, queued packets are flushed and the write side is shut down
+    /// afterwards, websocket sessions send a close frame first. The read half is dropped
+    pub async fn close(self) -> NetResult<()> {
+        let ShroomStream { mut w, r, .. } = self;
+        w.close().await?;
+        drop(r);
         Ok(())
     }
+
+    /// Closes the session like `close` and then discards the remaining input,
+    /// until the peer closes the connection or the drain timeout expires
+    pub async fn shutdown(self, drain_timeout: Duration) -> NetResult<CloseOutcome> {
+        let ShroomStream { mut w, mut r, .. } = self;
+        match w.close().await {
+            Err(err) if is_reset(&err) => return Ok(CloseOutcome::Reset),
+            res => res?,
+        }
+
+        let drain = async move {
+            while let Some(res) = r.next().await {
+                match res {
+                    Err(err) if is_reset(&err) => return Ok(CloseOutcome::Reset),
+                    Err(err) => return Err(err),
+                    Ok(_) => {}
+                }
+            }
+            Ok(CloseOutcome::Clean)
+        };
+        tokio::time::timeout(drain_timeout, drain)
+            .await
+            .unwrap_or(Ok(CloseOutcome::TimedOut))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use bytes::Bytes;
     use futures::{SinkExt, StreamExt};
-    use shroom_crypto::{net::net_cipher::CRYPT_NONE, SharedCryptoContext};
+    use shroom_crypto::{
+        net::net_cipher::{CRYPT_AES, CRYPT_NONE},
+        SharedCryptoContext,
+    };
     use std::{
         net::{IpAddr, Ipv4Addr},
         ops::Deref,
         sync::Arc,
+        time::Duration,
     };
+    use shroom_pkt::util::packet_buf::PacketBuf;
     use turmoil::net::{TcpListener, TcpStream};
 
     use crate::codec::{
-        legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodec, LegacyCodecNoShanda},
+        fault::{FaultConfig, FaultTransport},
+        legacy::{
+            handshake::{DefaultHandshakeFormat, ExtendedHandshakeFormat, HandshakeFormat},
+            handshake_gen::{BasicHandshakeGenerator, HandshakeGenerator},
+            LegacyCodec, LegacyCodecNoShanda, LocaleCode,
+        },
+        memory::MemoryTransport,
         websocket::WebSocketCodec,
         ShroomCodec,
     };
 
+    use super::CloseOutcome;
+
     const PORT: u16 = 1738;
 
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Ping(u32);
+    shroom_pkt::packet_wrap!(Ping<>, u32, u32);
+
+    impl From<u32> for Ping {
+        fn from(v: u32) -> Self {
+            Self(v)
+        }
+    }
+
+    impl From<Ping> for u32 {
+        fn from(v: Ping) -> Self {
+            v.0
+        }
+    }
+
+    impl shroom_pkt::HasOpCode for Ping {
+        type OpCode = u16;
+
+        const OPCODE: u16 = 0x11;
+    }
+
     async fn bind() -> std::result::Result<TcpListener, std::io::Error> {
         TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await
     }
@@ -201,6 +615,133 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn echo_ext_handshake() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = bind().await?;
+            let hshake_gen = BasicHandshakeGenerator::new(65.into(), "1.2.65", LocaleCode::Korea)
+                .with_extra(&[1, 2, 3]);
+            // Closures can be used as generator aswell
+            let legacy = LegacyCodec::<CRYPT_AES, turmoil::net::TcpStream, _>::new(
+                SharedCryptoContext::default(),
+                move || hshake_gen.generate_handshake(),
+            )
+            .with_handshake_format(ExtendedHandshakeFormat::default());
+            loop {
+                let socket = listener.accept().await.unwrap().0;
+                let mut sess = legacy.create_server(socket).await?;
+                while let Ok(pkt) = sess.next().await.unwrap() {
+                    sess.send(pkt).await.unwrap();
+                }
+            }
+        });
+
+        sim.client("client", async move {
+            let legacy = LegacyCodecNoShanda::<turmoil::net::TcpStream>::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v83(),
+            )
+            .with_handshake_format(ExtendedHandshakeFormat::default());
+            let socket = TcpStream::connect(("server", PORT)).await.unwrap();
+            let (mut sess, hshake) = legacy.create_client_with_handshake(socket).await?;
+            assert_eq!(hshake.sub_version.as_str(), "1.2.65");
+            assert_eq!(hshake.extra.as_slice(), &[1, 2, 3]);
+
+            sess.send(Bytes::from_static(&[1, 2])).await?;
+            assert_eq!(sess.next().await.unwrap()?.deref(), &[1, 2]);
+            Ok(())
+        });
+
+        sim.run().unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn echo_msg() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = bind().await?;
+            let legacy = LegacyCodecNoShanda::<turmoil::net::TcpStream>::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v83(),
+            );
+            let socket = listener.accept().await.unwrap().0;
+            let mut sess = legacy.create_server(socket).await?;
+            let msg = sess.recv_msg().await?;
+            assert_eq!(msg.opcode_value(), 0x11);
+            sess.send(msg).await?;
+            let ping: Ping = sess.recv_as().await?;
+            sess.send_msg(Ping(ping.0 + 1)).await?;
+            Ok(())
+        });
+
+        sim.client("client", async move {
+            let legacy = LegacyCodecNoShanda::<turmoil::net::TcpStream>::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v83(),
+            );
+            let socket = TcpStream::connect(("server", PORT)).await.unwrap();
+            let mut sess = legacy.create_client(socket).await?;
+            sess.send_msg(Ping(1)).await?;
+            assert_eq!(sess.recv_as::<Ping>().await?, Ping(1));
+            sess.send_msg(Ping(2)).await?;
+            assert_eq!(sess.recv_as::<Ping>().await?, Ping(3));
+            // Stream is closed by the server
+            assert!(sess.recv_msg().await.is_err());
+            Ok(())
+        });
+
+        sim.run().unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn echo_batch() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = bind().await?;
+            let legacy = LegacyCodecNoShanda::<turmoil::net::TcpStream>::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v83(),
+            );
+            let socket = listener.accept().await.unwrap().0;
+            let mut sess = legacy.create_server(socket).await?;
+            let mut buf = PacketBuf::default();
+            for _ in 0..3 {
+                let ping: Ping = sess.recv_as().await?;
+                buf.encode(ping)?;
+            }
+            sess.send_packet_buf(&buf).await?;
+            Ok(())
+        });
+
+        sim.client("client", async move {
+            let legacy = LegacyCodecNoShanda::<turmoil::net::TcpStream>::new(
+                SharedCryptoContext::default(),
+                BasicHandshakeGenerator::v83(),
+            );
+            let socket = TcpStream::connect(("server", PORT)).await.unwrap();
+            let mut sess = legacy.create_client(socket).await?;
+            sess.send_msg_batch([Ping(1), Ping(2)]).await?;
+            sess.send_batch([Bytes::from_static(&[0x11, 0, 3, 0, 0, 0])])
+                .await?;
+            for i in 1..=3 {
+                assert_eq!(sess.recv_as::<Ping>().await?, Ping(i));
+            }
+            Ok(())
+        });
+
+        sim.run().unwrap();
+
+        Ok(())
+    }
+
     #[test]
     fn echo_ws() -> anyhow::Result<()> {
         const ECHO_DATA: [&'static [u8]; 5] = [&[], &[0xFF; 4096], &[], &[1, 2], &[0x0; 1024]];
@@ -241,4 +782,137 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn echo_ws_packet() -> anyhow::Result<()> {
+        const ECHO_DATA: [&[u8]; 5] = [&[], &[0xFF; 4096], &[], &[1, 2], &[0x0; 1024]];
+
+        let uri = http::Uri::from_static("ws://127.0.0.1");
+        let legacy = Arc::new(WebSocketCodec::<turmoil::net::TcpStream>::new(uri.clone()));
+
+        let mut sim = turmoil::Builder::new().build();
+
+        sim.host("server", || async move {
+            let listener = bind().await?;
+            let uri = http::Uri::from_static("ws://127.0.0.1");
+
+            let legacy = WebSocketCodec::<turmoil::net::TcpStream>::new(uri.clone());
+            loop {
+                let socket = listener.accept().await.unwrap().0;
+                let mut sess = legacy.create_server(socket).await?;
+                // Echo the owned packets without copying them
+                while let Ok(pkt) = sess.next().await.unwrap() {
+                    sess.send_packet(pkt).await.unwrap();
+                }
+            }
+        });
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await.unwrap();
+            let mut sess = legacy.create_client(socket).await.unwrap();
+            for (i, data) in ECHO_DATA.iter().enumerate() {
+                sess.send(Bytes::from_static(data)).await.unwrap();
+                let pkt = sess.next().await.unwrap().unwrap();
+                assert_eq!(pkt.deref(), *data, "failed at: {i}");
+            }
+
+            Ok(())
+        });
+
+        sim.run().unwrap();
+
+        Ok(())
+    }
+
+    fn memory_pair() -> (MemoryTransport, MemoryTransport) {
+        MemoryTransport::pair(
+            "192.168.0.1:56324".parse().unwrap(),
+            "10.0.0.1:8484".parse().unwrap(),
+        )
+    }
+
+    async fn shutdown_clean<C: ShroomCodec<Transport = MemoryTransport> + Unpin>(
+        codec: C,
+    ) -> anyhow::Result<()> {
+        let (client, server) = memory_pair();
+        let server = async {
+            let mut sess = codec.create_server(server).await?;
+            // The queued packet must be flushed before the write side is shut down
+            sess.feed(Bytes::from_static(&[1, 0])).await?;
+            anyhow::Ok(sess.shutdown(Duration::from_secs(1)).await?)
+        };
+        let client = async {
+            let mut sess = codec.create_client(client).await?;
+            assert_eq!(&sess.next().await.unwrap()?[..], &[1, 0]);
+            assert!(sess.next().await.is_none());
+            anyhow::Ok(sess.shutdown(Duration::from_secs(1)).await?)
+        };
+
+        let (server, client) = tokio::join!(server, client);
+        assert_eq!(server?, CloseOutcome::Clean);
+        assert_eq!(client?, CloseOutcome::Clean);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_clean_legacy() -> anyhow::Result<()> {
+        shutdown_clean(LegacyCodecNoShanda::<MemoryTransport>::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn shutdown_clean_ws() -> anyhow::Result<()> {
+        shutdown_clean(WebSocketCodec::<MemoryTransport>::new(
+            http::Uri::from_static("ws://10.0.0.1"),
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn shutdown_timeout() -> anyhow::Result<()> {
+        let codec = LegacyCodecNoShanda::<MemoryTransport>::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        );
+        let (client, server) = memory_pair();
+        let client = codec.create_client(client);
+        let (client, server) = tokio::join!(client, codec.create_server(server));
+        let (mut client, server) = (client?, server?);
+
+        // Input is discarded until the timeout expires, because the client never closes
+        client.send(Bytes::from_static(&[1, 0])).await?;
+        assert_eq!(
+            server.shutdown(Duration::from_millis(50)).await?,
+            CloseOutcome::TimedOut
+        );
+        assert!(client.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_reset() -> anyhow::Result<()> {
+        type Transport = FaultTransport<MemoryTransport>;
+        let codec = LegacyCodecNoShanda::<Transport>::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        );
+        let hshake = BasicHandshakeGenerator::v83().generate_handshake();
+        let hshake_len = DefaultHandshakeFormat.to_buf(&hshake)?.len();
+
+        // The connection is reset right after the handshake was sent
+        let (client, server) = memory_pair();
+        let client = FaultTransport::new(client, FaultConfig::new(0));
+        let server = FaultTransport::new(server, FaultConfig::new(0).with_drop_after(hshake_len));
+        let server = codec.create_server_with_handshake(server, &hshake).await?;
+        let mut client = codec.create_client(client).await?;
+        client.send(Bytes::from_static(&[1, 0])).await?;
+        assert_eq!(
+            server.shutdown(Duration::from_secs(1)).await?,
+            CloseOutcome::Reset
+        );
+        Ok(())
+    }
 }
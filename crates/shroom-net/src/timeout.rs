@@ -0,0 +1,57 @@
+use std::{future::Future, time::Duration};
+
+use crate::{NetError, NetResult};
+
+/// Kind of the timeout, which expired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// Handshake was not completed in time
+    Handshake,
+    /// No data was received in time
+    ReadIdle,
+    /// Pong was not received in time
+    Heartbeat,
+}
+
+impl std::fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Handshake => "handshake",
+            Self::ReadIdle => "read idle",
+            Self::Heartbeat => "heartbeat",
+        })
+    }
+}
+
+/// Timeouts for a session, `None` disables the timeout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionTimeouts {
+    /// Time to complete the handshake
+    pub handshake: Option<Duration>,
+    /// Maximum time between two received packets
+    pub read_idle: Option<Duration>,
+}
+
+impl SessionTimeouts {
+    /// Sets the handshake timeout
+    pub fn with_handshake(mut self, timeout: Duration) -> Self {
+        self.handshake = Some(timeout);
+        self
+    }
+
+    /// Sets the read idle timeout
+    pub fn with_read_idle(mut self, timeout: Duration) -> Self {
+        self.read_idle = Some(timeout);
+        self
+    }
+
+    /// Runs the handshake future with the handshake timeout
+    pub async fn handshake<T>(&self, fut: impl Future<Output = NetResult<T>>) -> NetResult<T> {
+        match self.handshake {
+            Some(timeout) => tokio::time::timeout(timeout, fut)
+                .await
+                .map_err(|_| NetError::Timeout(TimeoutKind::Handshake))?,
+            None => fut.await,
+        }
+    }
+}
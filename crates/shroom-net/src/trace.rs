@@ -0,0 +1,385 @@
+use std::{collections::HashMap, fmt::Debug, net::SocketAddr, sync::Arc};
+
+use shroom_pkt::analyzer::HexString;
+use tracing::{field::Empty, Level, Span};
+
+use crate::{codec::legacy::handshake::Handshake, NetError};
+
+/// Emits the event at the level, returns false If the level is disabled
+macro_rules! dyn_event {
+    ($level:expr, $($args:tt)*) => {{
+        let level = $level;
+        if level == Level::ERROR && tracing::enabled!(Level::ERROR) {
+            tracing::event!(Level::ERROR, $($args)*);
+            true
+        } else if level == Level::WARN && tracing::enabled!(Level::WARN) {
+            tracing::event!(Level::WARN, $($args)*);
+            true
+        } else if level == Level::INFO && tracing::enabled!(Level::INFO) {
+            tracing::event!(Level::INFO, $($args)*);
+            true
+        } else if level == Level::DEBUG && tracing::enabled!(Level::DEBUG) {
+            tracing::event!(Level::DEBUG, $($args)*);
+            true
+        } else if level == Level::TRACE && tracing::enabled!(Level::TRACE) {
+            tracing::event!(Level::TRACE, $($args)*);
+            true
+        } else {
+            false
+        }
+    }};
+}
+
+/// Checks whether events at the level are enabled
+fn level_enabled(level: Level) -> bool {
+    if level == Level::ERROR {
+        tracing::enabled!(Level::ERROR)
+    } else if level == Level::WARN {
+        tracing::enabled!(Level::WARN)
+    } else if level == Level::INFO {
+        tracing::enabled!(Level::INFO)
+    } else if level == Level::DEBUG {
+        tracing::enabled!(Level::DEBUG)
+    } else {
+        tracing::enabled!(Level::TRACE)
+    }
+}
+
+/// Registry, which resolves opcodes to names for logging
+#[derive(Debug, Clone, Default)]
+pub struct OpcodeRegistry {
+    names: HashMap<u16, String>,
+}
+
+impl OpcodeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a registry from opcodes, which are named by their `Debug` output
+    pub fn from_opcodes<OP: Into<u16> + Debug>(opcodes: impl IntoIterator<Item = OP>) -> Self {
+        opcodes
+            .into_iter()
+            .map(|op| {
+                let name = format!("{op:?}");
+                (op.into(), name)
+            })
+            .collect()
+    }
+
+    /// Adds the name of the opcode
+    pub fn with_name(mut self, opcode: impl Into<u16>, name: impl Into<String>) -> Self {
+        self.insert(opcode, name);
+        self
+    }
+
+    /// Adds the name of the opcode, an existing name is replaced
+    pub fn insert(&mut self, opcode: impl Into<u16>, name: impl Into<String>) {
+        self.names.insert(opcode.into(), name.into());
+    }
+
+    /// Gets the name of the opcode
+    pub fn name(&self, opcode: u16) -> Option<&str> {
+        self.names.get(&opcode).map(String::as_str)
+    }
+}
+
+impl<OP: Into<u16>, S: Into<String>> FromIterator<(OP, S)> for OpcodeRegistry {
+    fn from_iter<I: IntoIterator<Item = (OP, S)>>(iter: I) -> Self {
+        let mut registry = Self::new();
+        for (op, name) in iter {
+            registry.insert(op, name);
+        }
+        registry
+    }
+}
+
+/// Opcode of a packet, packets without an opcode are shown as `-`
+struct Opcode(Option<u16>);
+
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(op) => write!(f, "{op:#06x}"),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+/// Logs the packets of sessions, each packet is logged at `DEBUG`
+/// with the opcode, Its name and the length
+#[derive(Debug, Clone, Default)]
+pub struct PacketTracer {
+    opcodes: OpcodeRegistry,
+    hex_dump: Option<Level>,
+}
+
+impl PacketTracer {
+    pub fn new(opcodes: OpcodeRegistry) -> Self {
+        Self {
+            opcodes,
+            hex_dump: None,
+        }
+    }
+
+    /// Logs packets with a hex dump at the level instead, If It is enabled
+    pub fn with_hex_dump(mut self, level: Level) -> Self {
+        self.hex_dump = Some(level);
+        self
+    }
+
+    /// Gets the opcode registry
+    pub fn opcodes(&self) -> &OpcodeRegistry {
+        &self.opcodes
+    }
+
+    /// Whether `packet` logs anything with the current subscriber
+    pub fn enabled(&self) -> bool {
+        tracing::enabled!(Level::DEBUG) || self.hex_dump.is_some_and(level_enabled)
+    }
+
+    /// Logs the incoming or outgoing packet
+    pub fn packet(&self, dir: &'static str, data: &[u8]) {
+        let opcode = data.get(..2).map(|op| u16::from_le_bytes([op[0], op[1]]));
+        let name = opcode
+            .and_then(|op| self.opcodes.name(op))
+            .unwrap_or("Unknown");
+        let (opcode, len) = (Opcode(opcode), data.len());
+
+        let dumped = self.hex_dump.is_some_and(|level| {
+            dyn_event!(
+                level,
+                dir,
+                %opcode,
+                name,
+                len,
+                data = %HexString::<true>::new(data),
+                "Packet"
+            )
+        });
+        if !dumped {
+            tracing::debug!(dir, %opcode, name, len, "Packet");
+        }
+    }
+}
+
+/// Creates the span of a session, the handshake is recorded
+/// by the legacy codecs If they run within the span
+pub fn session_span(peer: SocketAddr) -> Span {
+    tracing::info_span!(
+        "session",
+        %peer,
+        version = Empty,
+        sub_version = Empty,
+        locale = Empty
+    )
+}
+
+/// Records the handshake in the current session span
+pub(crate) fn record_handshake(hshake: &Handshake) {
+    let span = Span::current();
+    span.record("version", hshake.version.raw());
+    span.record("sub_version", hshake.sub_version.as_str());
+    span.record("locale", tracing::field::debug(hshake.locale));
+}
+
+/// Tracer of a single session
+pub(crate) struct SessionTracer {
+    tracer: Arc<PacketTracer>,
+    span: Span,
+}
+
+impl SessionTracer {
+    pub(crate) fn new(tracer: Arc<PacketTracer>, span: Span) -> Self {
+        Self { tracer, span }
+    }
+
+    pub(crate) fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Whether packets are logged within the session span
+    pub(crate) fn enabled(&self) -> bool {
+        let _span = self.span.enter();
+        self.tracer.enabled()
+    }
+
+    pub(crate) fn packet_in(&self, data: &[u8]) {
+        let _span = self.span.enter();
+        self.tracer.packet("in", data);
+    }
+
+    pub(crate) fn packet_out(&self, data: &[u8]) {
+        let _span = self.span.enter();
+        self.tracer.packet("out", data);
+    }
+
+    pub(crate) fn error(&self, err: &NetError) {
+        let _span = self.span.enter();
+        tracing::warn!(?err, "Failed to read packet");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    };
+
+    use futures::SinkExt;
+    use shroom_crypto::SharedCryptoContext;
+    use shroom_pkt::Packet;
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Level, Metadata, Span, Subscriber,
+    };
+
+    use crate::{
+        codec::{
+            legacy::{handshake_gen::BasicHandshakeGenerator, LegacyCodecNoShanda, MAX_PACKET_LEN},
+            memory::MemoryTransport,
+            ShroomCodec,
+        },
+        NetError,
+    };
+
+    use super::{OpcodeRegistry, PacketTracer};
+
+    /// Subscriber, which captures the fields of all events
+    #[derive(Default)]
+    struct Capture {
+        events: Mutex<Vec<(Level, String)>>,
+        ids: AtomicU64,
+    }
+
+    struct FieldsVisitor(String);
+
+    impl Visit for FieldsVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!("{}={:?} ", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, meta: &Metadata<'_>) -> bool {
+            *meta.level() <= Level::DEBUG
+        }
+
+        fn max_level_hint(&self) -> Option<tracing::level_filters::LevelFilter> {
+            Some(tracing::level_filters::LevelFilter::DEBUG)
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(self.ids.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut visitor = FieldsVisitor(String::new());
+            event.record(&mut visitor);
+            let level = *event.metadata().level();
+            self.events.lock().unwrap().push((level, visitor.0));
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn packet_events() {
+        let capture = Arc::new(Capture::default());
+        let opcodes = OpcodeRegistry::new().with_name(0x11u16, "Ping");
+        let tracer = PacketTracer::new(opcodes);
+        // Hex dumps at trace are disabled by the subscriber
+        let dump_trace = tracer.clone().with_hex_dump(Level::TRACE);
+        let dump_debug = tracer.clone().with_hex_dump(Level::DEBUG);
+
+        tracing::subscriber::with_default(capture.clone(), || {
+            tracer.packet("in", &[0x11, 0, 1]);
+            tracer.packet("out", &[0x12, 0]);
+            tracer.packet("out", &[]);
+            dump_trace.packet("in", &[0x11, 0, 1]);
+            dump_debug.packet("in", &[0x11, 0, 0xff]);
+        });
+
+        let events = capture.events.lock().unwrap();
+        let expected = [
+            "message=Packet dir=\"in\" opcode=0x0011 name=\"Ping\" len=3 ",
+            "message=Packet dir=\"out\" opcode=0x0012 name=\"Unknown\" len=2 ",
+            "message=Packet dir=\"out\" opcode=- name=\"Unknown\" len=0 ",
+            "message=Packet dir=\"in\" opcode=0x0011 name=\"Ping\" len=3 ",
+            "message=Packet dir=\"in\" opcode=0x0011 name=\"Ping\" len=3 data=11 00 ff ",
+        ];
+        assert_eq!(events.len(), expected.len());
+        for ((level, fields), expected) in events.iter().zip(expected) {
+            assert_eq!(*level, Level::DEBUG);
+            assert_eq!(fields, expected);
+        }
+    }
+
+    #[test]
+    fn enabled() {
+        let capture = Arc::new(Capture::default());
+        let tracer = PacketTracer::default();
+        assert!(!tracer.enabled());
+        assert!(!tracer.clone().with_hex_dump(Level::TRACE).enabled());
+        tracing::subscriber::with_default(capture, || {
+            assert!(tracer.enabled());
+            assert!(tracer.clone().with_hex_dump(Level::TRACE).enabled());
+        });
+    }
+
+    #[tokio::test]
+    async fn rejected_send() -> anyhow::Result<()> {
+        let codec = LegacyCodecNoShanda::<MemoryTransport>::new(
+            SharedCryptoContext::default(),
+            BasicHandshakeGenerator::v83(),
+        );
+        let (client, server) =
+            MemoryTransport::pair("192.168.0.1:56324".parse()?, "10.0.0.1:8484".parse()?);
+        let (sess, _client) =
+            tokio::join!(codec.create_server(server), codec.create_client(client));
+        let mut sess = sess?.with_tracer(Arc::new(PacketTracer::default()), Span::none());
+
+        let capture = Arc::new(Capture::default());
+        let _guard = tracing::subscriber::set_default(capture.clone());
+        let err = sess.send(vec![0; MAX_PACKET_LEN + 1]).await.unwrap_err();
+        assert!(matches!(err, NetError::FrameSize(_)));
+        sess.send_packet(Packet::from_static(&[0x11, 0])).await?;
+
+        // Only the packet, which was accepted by the sink is logged
+        let events = capture.events.lock().unwrap();
+        let out = events
+            .iter()
+            .filter(|(_, fields)| fields.contains("dir=\"out\""))
+            .collect::<Vec<_>>();
+        assert_eq!(out.len(), 1);
+        assert!(out[0].1.contains("len=2"));
+        Ok(())
+    }
+
+    #[test]
+    fn registry() {
+        #[derive(Debug, Clone, Copy)]
+        enum Op {
+            Ping = 0x11,
+            Pong = 0x12,
+        }
+
+        impl From<Op> for u16 {
+            fn from(op: Op) -> Self {
+                op as u16
+            }
+        }
+
+        let registry = OpcodeRegistry::from_opcodes([Op::Ping, Op::Pong]);
+        assert_eq!(registry.name(0x11), Some("Ping"));
+        assert_eq!(registry.name(0x12), Some("Pong"));
+        assert_eq!(registry.name(0x13), None);
+    }
+}
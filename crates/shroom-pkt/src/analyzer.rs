@@ -25,7 +25,12 @@ impl<const SPACE: bool> std::fmt::Display for HexString<'_, SPACE> {
     }
 }
 
-impl<const SPACE: bool> HexString<'_, SPACE> {
+impl<'a, const SPACE: bool> HexString<'a, SPACE> {
+    /// Creates a hex string of the data, bytes are separated by a space If `SPACE` is set
+    pub fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
     const fn size_per_byte() -> usize {
         if SPACE {
             3
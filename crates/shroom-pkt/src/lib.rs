@@ -13,6 +13,7 @@ pub mod opcode;
 pub mod pkt;
 pub mod proto;
 pub mod reader;
+pub mod router;
 pub mod test_util;
 pub mod util;
 pub mod writer;
@@ -40,6 +40,12 @@ impl From<Bytes> for Packet {
     }
 }
 
+impl From<Packet> for Bytes {
+    fn from(value: Packet) -> Self {
+        value.0
+    }
+}
+
 impl From<BytesMut> for Packet {
     fn from(value: BytesMut) -> Self {
         Self(value.freeze())
@@ -71,9 +77,41 @@ impl Message {
         PacketReader::new(self.payload())
     }
 
-    /// Decodes the payload
+    /// Decodes the payload, failures are logged with the packet analyzer
+    /// If the `tracing` feature is enabled
     pub fn decode<'de, T: DecodePacket<'de>>(&'de self) -> Result<T, Error> {
-        T::decode(&mut self.reader())
+        let res = T::decode(&mut self.reader());
+        #[cfg(feature = "tracing")]
+        if let Err(err) = &res {
+            self.trace_decode_error::<T>(err);
+        }
+        res
+    }
+
+    #[cfg(feature = "tracing")]
+    fn trace_decode_error<T>(&self, err: &Error) {
+        let ty = std::any::type_name::<T>();
+        let opcode = self.opcode_value();
+        match err {
+            Error::EOF(eof) => tracing::warn!(
+                opcode,
+                ty,
+                "Failed to decode message:\n{}",
+                eof.analytics(self.payload())
+            ),
+            err => tracing::warn!(
+                opcode,
+                ty,
+                data = %crate::analyzer::HexString::<true>::new(self.payload()),
+                "Failed to decode message: {err}"
+            ),
+        }
+    }
+}
+
+impl From<Message> for Packet {
+    fn from(value: Message) -> Self {
+        value.0
     }
 }
 
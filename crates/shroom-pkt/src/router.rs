@@ -0,0 +1,218 @@
+use std::{collections::HashMap, future::Future, marker::PhantomData, pin::Pin};
+
+use thiserror::Error;
+
+use crate::{
+    pkt::{DecodeMessage, Message},
+    Error, HasOpCode, ShroomOpCode,
+};
+
+/// Boxed future, which is returned by the handlers
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Result of a handler
+pub type HandlerResult = anyhow::Result<()>;
+
+/// Error which occured while routing a message
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error("Unable to decode message {opcode:X}: {err}")]
+    Decode { opcode: u16, err: Error },
+    #[error("No handler for opcode {0:X}")]
+    Unhandled(u16),
+    #[error("Handler for opcode {opcode:X} failed: {err}")]
+    Handler { opcode: u16, err: anyhow::Error },
+}
+
+impl RouteError {
+    /// Gets the opcode of the message which failed
+    pub fn opcode(&self) -> u16 {
+        match self {
+            Self::Decode { opcode, .. } | Self::Handler { opcode, .. } => *opcode,
+            Self::Unhandled(opcode) => *opcode,
+        }
+    }
+}
+
+type DynHandler<Ctx> = Box<
+    dyn for<'a> Fn(&'a mut Ctx, Message) -> BoxFuture<'a, Result<(), RouteError>> + Send + Sync,
+>;
+
+type DynFallback<Ctx> =
+    Box<dyn for<'a> Fn(&'a mut Ctx, Message) -> BoxFuture<'a, HandlerResult> + Send + Sync>;
+
+/// Router, which dispatches messages by their opcode to the handler
+/// registered for the message type
+///
+/// Example:
+/// ```ignore
+/// let mut router = PacketRouter::<Session, RecvOpCode>::new();
+/// router.on::<LoginReq, _>(|sess, req| Box::pin(sess.handle_login(req)));
+/// router.handle(&mut sess, msg).await?;
+/// ```
+pub struct PacketRouter<Ctx, Op: ShroomOpCode> {
+    handlers: HashMap<u16, DynHandler<Ctx>>,
+    fallback: Option<DynFallback<Ctx>>,
+    _marker: PhantomData<fn(Op)>,
+}
+
+impl<Ctx: Send, Op: ShroomOpCode> Default for PacketRouter<Ctx, Op> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Ctx, Op: ShroomOpCode> std::fmt::Debug for PacketRouter<Ctx, Op> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketRouter")
+            .field("opcodes", &self.handlers.keys())
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+impl<Ctx: Send, Op: ShroomOpCode> PacketRouter<Ctx, Op> {
+    /// Creates an empty router
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            fallback: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Registers the handler for the message type `T`,
+    /// a previously registered handler for the same opcode is replaced
+    pub fn on<T, F>(&mut self, handler: F) -> &mut Self
+    where
+        T: for<'de> DecodeMessage<'de> + HasOpCode<OpCode = Op> + Send + 'static,
+        F: for<'a> Fn(&'a mut Ctx, T) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static,
+    {
+        let opcode: u16 = T::OPCODE.into();
+        self.handlers.insert(
+            opcode,
+            Box::new(move |ctx, msg| match T::decode_message(&msg) {
+                Ok(msg) => {
+                    let fut = handler(ctx, msg);
+                    Box::pin(
+                        async move { fut.await.map_err(|err| RouteError::Handler { opcode, err }) },
+                    )
+                }
+                Err(err) => Box::pin(std::future::ready(Err(RouteError::Decode { opcode, err }))),
+            }),
+        );
+        self
+    }
+
+    /// Sets the fallback, which is called for messages without a handler
+    pub fn fallback<F>(&mut self, fallback: F) -> &mut Self
+    where
+        F: for<'a> Fn(&'a mut Ctx, Message) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static,
+    {
+        self.fallback = Some(Box::new(fallback));
+        self
+    }
+
+    /// Checks whether a handler is registered for the opcode
+    pub fn has_handler(&self, op: Op) -> bool {
+        self.handlers.contains_key(&op.into())
+    }
+
+    /// Dispatches the message to the registered handler or the fallback
+    pub async fn handle(&self, ctx: &mut Ctx, msg: Message) -> Result<(), RouteError> {
+        let opcode = msg.opcode_value();
+        if let Some(handler) = self.handlers.get(&opcode) {
+            return handler(ctx, msg).await;
+        }
+
+        match self.fallback {
+            Some(ref fallback) => fallback(ctx, msg)
+                .await
+                .map_err(|err| RouteError::Handler { opcode, err }),
+            None => Err(RouteError::Unhandled(opcode)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use derive_more::{From, Into};
+    use futures::executor::block_on;
+
+    use super::{PacketRouter, RouteError};
+    use crate::{opcode::HasOpCode, packet_wrap, pkt::EncodeMessage, Packet};
+
+    #[derive(Debug, Copy, Clone, From, Into)]
+    pub struct Add(u8);
+    packet_wrap!(Add<>, u8, u8);
+
+    impl HasOpCode for Add {
+        type OpCode = u16;
+
+        const OPCODE: u16 = 1;
+    }
+
+    #[derive(Debug, Copy, Clone, From, Into)]
+    pub struct Sub(u8);
+    packet_wrap!(Sub<>, u8, u8);
+
+    impl HasOpCode for Sub {
+        type OpCode = u16;
+
+        const OPCODE: u16 = 2;
+    }
+
+    fn router() -> PacketRouter<i32, u16> {
+        let mut router = PacketRouter::new();
+        router
+            .on::<Add, _>(|ctx, v| {
+                Box::pin(async move {
+                    *ctx += v.0 as i32;
+                    Ok(())
+                })
+            })
+            .on::<Sub, _>(|ctx, v| {
+                Box::pin(async move {
+                    *ctx -= v.0 as i32;
+                    Ok(())
+                })
+            });
+        router
+    }
+
+    #[test]
+    fn route() -> anyhow::Result<()> {
+        let router = router();
+        let mut ctx = 0;
+        block_on(router.handle(&mut ctx, Add(3).to_message()?))?;
+        block_on(router.handle(&mut ctx, Sub(1).to_message()?))?;
+        assert_eq!(ctx, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn route_errors() -> anyhow::Result<()> {
+        let mut router = router();
+        let mut ctx = 0;
+
+        // Missing payload
+        let msg = Packet::from_static(&[1, 0]).try_into()?;
+        let err = block_on(router.handle(&mut ctx, msg)).unwrap_err();
+        assert!(matches!(err, RouteError::Decode { opcode: 1, .. }));
+
+        let msg = Packet::from_static(&[3, 0]).try_into()?;
+        let err = block_on(router.handle(&mut ctx, msg)).unwrap_err();
+        assert!(matches!(err, RouteError::Unhandled(3)));
+
+        router.fallback(|ctx, msg| {
+            Box::pin(async move {
+                *ctx = msg.opcode_value() as i32;
+                Ok(())
+            })
+        });
+        let msg = Packet::from_static(&[3, 0]).try_into()?;
+        block_on(router.handle(&mut ctx, msg))?;
+        assert_eq!(ctx, 3);
+        Ok(())
+    }
+}